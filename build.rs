@@ -1,5 +1,9 @@
 extern crate pkg_config;
 
+use std::env;
+
 fn main () {
-    pkg_config::probe_library("fuse").unwrap();
+    if env::var_os("CARGO_FEATURE_LIBFUSE").is_some() {
+        pkg_config::probe_library("fuse").unwrap();
+    }
 }
@@ -8,6 +8,7 @@ use std::ffi::OsStr;
 use libc::ENOENT;
 use time::Timespec;
 use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
+use fuse::{MountOption, MountOptions};
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
 
@@ -28,6 +29,7 @@ const HELLO_DIR_ATTR: FileAttr = FileAttr {
     gid: 20,
     rdev: 0,
     flags: 0,
+    blksize: 512,
 };
 
 const HELLO_TXT_CONTENT: &'static str = "Hello World!\n";
@@ -47,6 +49,7 @@ const HELLO_TXT_ATTR: FileAttr = FileAttr {
     gid: 20,
     rdev: 0,
     flags: 0,
+    blksize: 512,
 };
 
 struct HelloFS;
@@ -93,5 +96,6 @@ impl Filesystem for HelloFS {
 fn main () {
     env_logger::init().unwrap();
     let mountpoint = env::args_os().nth(1).unwrap();
-    fuse::mount(HelloFS, &mountpoint, &[]).unwrap();
+    let options = MountOptions::new().add(MountOption::RO).add(MountOption::FSName("hello".to_string()));
+    fuse::mount(HelloFS, &mountpoint, &options).unwrap();
 }
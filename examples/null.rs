@@ -2,7 +2,7 @@ extern crate env_logger;
 extern crate fuse;
 
 use std::env;
-use fuse::Filesystem;
+use fuse::{Filesystem, MountOptions};
 
 struct NullFS;
 
@@ -12,5 +12,5 @@ impl Filesystem for NullFS {
 fn main () {
     env_logger::init().unwrap();
     let mountpoint = env::args_os().nth(1).unwrap();
-    fuse::mount(NullFS, &mountpoint, &[]).unwrap();
+    fuse::mount(NullFS, &mountpoint, &MountOptions::new()).unwrap();
 }
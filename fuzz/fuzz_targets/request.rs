@@ -0,0 +1,21 @@
+//!
+//! Fuzz parsing and dispatching of kernel requests. Every input is sent as a
+//! single request to an initialized filesystem that uses the default
+//! implementations of all operations.
+//!
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fuse;
+
+use fuse::{Filesystem, MockKernel};
+
+struct NullFS;
+
+impl Filesystem for NullFS {}
+
+fuzz_target!(|data: &[u8]| {
+    let mut kernel = MockKernel::new(NullFS).unwrap();
+    kernel.dispatch_raw(data);
+});
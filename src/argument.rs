@@ -3,8 +3,9 @@
 //! structures.
 //!
 
-use std::mem;
+use std::{mem, ptr};
 use std::ffi::OsStr;
+use std::mem::size_of;
 use std::path::Path;
 use std::os::unix::ffi::OsStrExt;
 
@@ -19,26 +20,43 @@ impl<'a> ArgumentIterator<'a> {
         ArgumentIterator { data: data }
     }
 
-    /// Fetch a typed argument
-    pub fn fetch<T> (&mut self) -> &'a T {
-        let len = mem::size_of::<T>();
-        assert!(len <= self.data.len(), "out of data while fetching typed argument");
+    /// Fetch a typed argument. The argument is copied, so the data doesn't need
+    /// to be aligned. Returns None if there's not enough data left.
+    pub fn fetch<T> (&mut self) -> Option<T> {
+        self.fetch_compat(size_of::<T>())
+    }
+
+    /// Fetch a typed argument of which only the first len bytes are present. Kernels
+    /// with an older ABI version send shorter versions of some structs, so the
+    /// missing trailing fields are filled with zeroes. Returns None if there's not
+    /// enough data left.
+    pub fn fetch_compat<T> (&mut self, len: usize) -> Option<T> {
+        assert!(len <= size_of::<T>(), "compat size exceeds size of typed argument");
+        if len > self.data.len() {
+            return None;
+        }
         let bytes = &self.data[..len];
         self.data = &self.data[len..];
-        unsafe { mem::transmute(bytes.as_ptr()) }
+        unsafe {
+            let mut arg: T = mem::zeroed();
+            ptr::copy_nonoverlapping(bytes.as_ptr(), &mut arg as *mut T as *mut u8, len);
+            Some(arg)
+        }
     }
 
-    /// Fetch a (zero-terminated) string (can be non-utf8)
-    pub fn fetch_str (&mut self) -> &'a OsStr {
-        let len = self.data.iter().position(|&c| c == 0).expect("out of data while fetching string argument");
+    /// Fetch a (zero-terminated) string (can be non-utf8). Returns None if there's
+    /// no terminating zero.
+    pub fn fetch_str (&mut self) -> Option<&'a OsStr> {
+        let len = self.data.iter().position(|&c| c == 0)?;
         let bytes = &self.data[..len];
         self.data = &self.data[len+1..];
-        OsStr::from_bytes(&bytes)
+        Some(OsStr::from_bytes(bytes))
     }
 
-    /// Fetch a (zero-terminated) path (can be non-utf8)
-    pub fn fetch_path (&mut self) -> &'a Path {
-        Path::new(self.fetch_str())
+    /// Fetch a (zero-terminated) path (can be non-utf8). Returns None if there's
+    /// no terminating zero.
+    pub fn fetch_path (&mut self) -> Option<&'a Path> {
+        self.fetch_str().map(Path::new)
     }
 
     /// Fetch a slice of all remaining data
@@ -56,44 +74,63 @@ mod test {
     use super::ArgumentIterator;
 
     static TEST_DATA: [u8; 12] = [0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, 0x62, 0x61, 0x7a, 0x00];
+    #[repr(C)]
     struct TestArgument { p1: u8, p2: u8, p3: u16 }
 
     #[test]
     fn generic_argument () {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg: &TestArgument = it.fetch();
+        let arg: TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
         assert_eq!(arg.p3, 0x006f);
-        let arg: &TestArgument = it.fetch();
+        let arg: TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x62);
         assert_eq!(arg.p2, 0x61);
         assert_eq!(arg.p3, 0x0072);
     }
 
+    #[test]
+    fn unaligned_argument () {
+        let mut it = ArgumentIterator::new(&TEST_DATA[1..]);
+        let arg: u64 = it.fetch().unwrap();
+        assert_eq!(arg, u64::from_le(0x6200_7261_6200_6f6f));
+    }
+
+    #[test]
+    fn compat_argument () {
+        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let arg: TestArgument = it.fetch_compat(2).unwrap();
+        assert_eq!(arg.p1, 0x66);
+        assert_eq!(arg.p2, 0x6f);
+        assert_eq!(arg.p3, 0x0000);
+        let arg = it.fetch_str().unwrap();
+        assert_eq!(arg, "o");
+    }
+
     #[test]
     fn string_argument () {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg = it.fetch_str();
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "foo");
-        let arg = it.fetch_str();
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "bar");
     }
 
     #[test]
     fn path_argument () {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg = it.fetch_path();
+        let arg = it.fetch_path().unwrap();
         assert_eq!(arg, Path::new("foo"));
-        let arg = it.fetch_path();
+        let arg = it.fetch_path().unwrap();
         assert_eq!(arg, Path::new("bar"));
     }
 
     #[test]
     fn data_argument () {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        it.fetch_str();
-        it.fetch_str();
+        it.fetch_str().unwrap();
+        it.fetch_str().unwrap();
         let arg = it.fetch_data();
         assert_eq!(arg, [0x62, 0x61, 0x7a, 0x00]);
     }
@@ -101,13 +138,24 @@ mod test {
     #[test]
     fn mixed_arguments () {
         let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg: &TestArgument = it.fetch();
+        let arg: TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
         assert_eq!(arg.p3, 0x006f);
-        let arg = it.fetch_str();
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "bar");
         let arg = it.fetch_data();
         assert_eq!(arg, [0x62, 0x61, 0x7a, 0x00]);
     }
+
+    #[test]
+    fn missing_arguments () {
+        let mut it = ArgumentIterator::new(&TEST_DATA[..6]);
+        assert!(it.fetch::<[u8; 8]>().is_none());
+        assert_eq!(it.fetch_str().unwrap(), "foo");
+        assert!(it.fetch_str().is_none());
+        assert!(it.fetch_path().is_none());
+        assert!(it.fetch::<TestArgument>().is_none());
+        assert_eq!(it.fetch_data(), [0x62, 0x61]);
+    }
 }
@@ -0,0 +1,874 @@
+//!
+//! Asynchronous filesystems. Instead of taking a reply object, the methods of an
+//! asynchronous filesystem return futures that resolve to the result of the
+//! operation. A driver runs the futures on a caller-provided executor and sends
+//! the replies to the kernel once the futures complete.
+//!
+
+use std::ffi::{OsStr, OsString};
+use std::future::{self, Future};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use libc::{c_int, ENOSYS};
+use time::Timespec;
+use {FallocateFlags, IoctlFlags, RenameFlags, WriteFlags, FileType, FileAttr, Filesystem, KernelConfig, Request, TimeOrNow};
+use notify::PollHandle;
+use reply::{ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen, ReplyWrite, ReplyStatfs};
+use reply::{ReplyCreate, ReplyLock, ReplyBmap, ReplyLseek, ReplyDirectory, ReplyDirectoryPlus, ReplyXattr};
+use reply::{ReplyIoctl, ReplyPoll};
+#[cfg(target_os = "macos")]
+use reply::ReplyXTimes;
+
+/// Future returned by the methods of an asynchronous filesystem. It resolves to
+/// the result of the operation or to an error code.
+pub type FsFuture<T> = Pin<Box<dyn Future<Output = Result<T, c_int>> + Send>>;
+
+/// Returns a future that immediately resolves to the given result
+fn ready<T: Send + 'static> (result: Result<T, c_int>) -> FsFuture<T> {
+    Box::pin(future::ready(result))
+}
+
+/// Directory entry (result of lookup, mknod, mkdir, symlink and link)
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Time the entry and its attributes may be cached
+    pub ttl: Timespec,
+    /// Attributes of the entry
+    pub attr: FileAttr,
+    /// Inode generation
+    pub generation: u64,
+}
+
+/// File attributes (result of getattr and setattr)
+#[derive(Clone, Debug)]
+pub struct Attr {
+    /// Time the attributes may be cached
+    pub ttl: Timespec,
+    /// Attributes
+    pub attr: FileAttr,
+}
+
+/// Opened file or directory (result of open and opendir)
+#[derive(Clone, Debug)]
+pub struct Opened {
+    /// File handle
+    pub fh: u64,
+    /// Open flags (FOPEN_*)
+    pub flags: u32,
+}
+
+/// Created file (result of create)
+#[derive(Clone, Debug)]
+pub struct Created {
+    /// Entry of the created file
+    pub entry: Entry,
+    /// File handle
+    pub fh: u64,
+    /// Open flags (FOPEN_*)
+    pub flags: u32,
+}
+
+/// Filesystem statistics (result of statfs)
+#[derive(Clone, Debug)]
+pub struct Statfs {
+    /// Total number of blocks
+    pub blocks: u64,
+    /// Number of free blocks
+    pub bfree: u64,
+    /// Number of free blocks for unprivileged users
+    pub bavail: u64,
+    /// Total number of inodes
+    pub files: u64,
+    /// Number of free inodes
+    pub ffree: u64,
+    /// Block size
+    pub bsize: u32,
+    /// Max length of names
+    pub namelen: u32,
+    /// Fragment size
+    pub frsize: u32,
+}
+
+/// File lock (result of getlk)
+#[derive(Clone, Debug)]
+pub struct Lock {
+    /// Start of the locked range
+    pub start: u64,
+    /// End of the locked range
+    pub end: u64,
+    /// Lock type
+    pub typ: u32,
+    /// Pid of the process holding the lock
+    pub pid: u32,
+}
+
+/// Extended attribute data or size (result of getxattr and listxattr)
+#[derive(Clone, Debug)]
+pub enum Xattr {
+    /// Size of the data if the requested size was 0
+    Size(u32),
+    /// Data of the attribute or list of attribute names
+    Data(Vec<u8>),
+}
+
+/// Entry of a directory listing (result of readdir)
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Offset to continue the listing after this entry
+    pub offset: u64,
+    /// Kind of file
+    pub kind: FileType,
+    /// Name of the entry
+    pub name: OsString,
+}
+
+/// Entry of a directory listing with attributes (result of readdirplus)
+#[derive(Clone, Debug)]
+pub struct DirEntryPlus {
+    /// Offset to continue the listing after this entry
+    pub offset: u64,
+    /// Name of the entry
+    pub name: OsString,
+    /// Entry with attributes
+    pub entry: Entry,
+}
+
+/// Result of an ioctl
+#[derive(Clone, Debug)]
+pub enum Ioctl {
+    /// Result value and output data
+    Data(i32, Vec<u8>),
+    /// Retry an unrestricted ioctl with the given input and output memory ranges
+    /// (pairs of address and length)
+    Retry(Vec<(u64, u64)>, Vec<(u64, u64)>),
+}
+
+/// Extended times (result of getxtimes, OS X only)
+#[cfg(target_os = "macos")]
+#[derive(Clone, Debug)]
+pub struct XTimes {
+    /// Backup time
+    pub bkuptime: Timespec,
+    /// Creation time
+    pub crtime: Timespec,
+}
+
+/// Executor to run the futures of an asynchronous filesystem on. This is
+/// implemented for closures, so e.g. the spawn function of a runtime can be used.
+pub trait Executor: Send + Sync + 'static {
+    /// Spawn the given future, which must be polled until it completes
+    fn spawn (&self, future: Pin<Box<dyn Future<Output = ()> + Send>>);
+}
+
+impl<F: Fn(Pin<Box<dyn Future<Output = ()> + Send>>) + Send + Sync + 'static> Executor for F {
+    fn spawn (&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        self(future)
+    }
+}
+
+/// Asynchronous filesystem trait.
+///
+/// Like `Filesystem`, but operations return futures instead of using reply
+/// objects. Methods take `&self` and may be called concurrently, since many
+/// operations can be in progress at once. Arguments that are borrowed from the
+/// request must be copied if they are needed by the returned future. See the
+/// `Filesystem` trait for a description of the methods.
+pub trait AsyncFilesystem: Sync + Send + 'static {
+    /// Initialize filesystem.
+    fn init (&self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    fn destroy (&self, _req: &Request) {
+    }
+
+    /// Look up a directory entry by name and get its attributes.
+    fn lookup (&self, _req: &Request, _parent: u64, _name: &OsStr) -> FsFuture<Entry> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Forget about an inode.
+    fn forget (&self, _req: &Request, _ino: u64, _nlookup: u64) {
+    }
+
+    /// Forget about multiple inodes. The default implementation calls forget for
+    /// every inode.
+    fn batch_forget (&self, req: &Request, nodes: &[(u64, u64)]) {
+        for &(ino, nlookup) in nodes {
+            self.forget(req, ino, nlookup);
+        }
+    }
+
+    /// Get file attributes.
+    fn getattr (&self, _req: &Request, _ino: u64) -> FsFuture<Attr> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Set file attributes.
+    fn setattr (&self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<Timespec>, _fh: Option<u64>, _kill_suidgid: bool, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>) -> FsFuture<Attr> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Read symbolic link.
+    fn readlink (&self, _req: &Request, _ino: u64) -> FsFuture<Vec<u8>> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Create file node.
+    fn mknod (&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32) -> FsFuture<Entry> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Create a directory.
+    fn mkdir (&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32) -> FsFuture<Entry> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Remove a file.
+    fn unlink (&self, _req: &Request, _parent: u64, _name: &OsStr) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Remove a directory.
+    fn rmdir (&self, _req: &Request, _parent: u64, _name: &OsStr) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Create a symbolic link.
+    fn symlink (&self, _req: &Request, _parent: u64, _name: &OsStr, _link: &Path) -> FsFuture<Entry> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Rename a file.
+    fn rename (&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: RenameFlags) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Create a hard link.
+    fn link (&self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr) -> FsFuture<Entry> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Open a file.
+    fn open (&self, _req: &Request, _ino: u64, _flags: u32) -> FsFuture<Opened> {
+        ready(Ok(Opened { fh: 0, flags: 0 }))
+    }
+
+    /// Read data.
+    fn read (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _size: u32) -> FsFuture<Vec<u8>> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Write data. Resolves to the number of bytes written.
+    fn write (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _write_flags: WriteFlags, _lock_owner: Option<u64>) -> FsFuture<u32> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Flush method.
+    fn flush (&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Release an open file.
+    fn release (&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> FsFuture<()> {
+        ready(Ok(()))
+    }
+
+    /// Synchronize file contents.
+    fn fsync (&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Open a directory.
+    fn opendir (&self, _req: &Request, _ino: u64, _flags: u32) -> FsFuture<Opened> {
+        ready(Ok(Opened { fh: 0, flags: 0 }))
+    }
+
+    /// Read directory. Entries that don't fit into the reply are dropped, the kernel
+    /// requests them again using the offset of the last entry that fit.
+    fn readdir (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64) -> FsFuture<Vec<DirEntry>> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Read directory with attributes. Like readdir, entries that don't fit into the
+    /// reply are dropped.
+    fn readdirplus (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64) -> FsFuture<Vec<DirEntryPlus>> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Release an open directory.
+    fn releasedir (&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32) -> FsFuture<()> {
+        ready(Ok(()))
+    }
+
+    /// Synchronize directory contents.
+    fn fsyncdir (&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Get file system statistics.
+    fn statfs (&self, _req: &Request, _ino: u64) -> FsFuture<Statfs> {
+        ready(Ok(Statfs { blocks: 0, bfree: 0, bavail: 0, files: 0, ffree: 0, bsize: 512, namelen: 255, frsize: 0 }))
+    }
+
+    /// Set an extended attribute.
+    fn setxattr (&self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Get an extended attribute.
+    fn getxattr (&self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32) -> FsFuture<Xattr> {
+        ready(Err(ENOSYS))
+    }
+
+    /// List extended attribute names.
+    fn listxattr (&self, _req: &Request, _ino: u64, _size: u32) -> FsFuture<Xattr> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr (&self, _req: &Request, _ino: u64, _name: &OsStr) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Check file access permissions.
+    fn access (&self, _req: &Request, _ino: u64, _mask: u32) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Create and open a file.
+    fn create (&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32) -> FsFuture<Created> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk (&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32) -> FsFuture<Lock> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    fn setlk (&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Map block index within file to block index within device.
+    fn bmap (&self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64) -> FsFuture<u64> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Allocate or deallocate space of an open file.
+    fn fallocate (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _length: u64, _mode: FallocateFlags) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Find the next data or hole of an open file.
+    fn lseek (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _whence: c_int) -> FsFuture<u64> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Copy a range of data from one open file to another. Resolves to the number
+    /// of bytes copied.
+    fn copy_file_range (&self, _req: &Request, _ino_in: u64, _fh_in: u64, _offset_in: u64, _ino_out: u64, _fh_out: u64, _offset_out: u64, _len: u64, _flags: u64) -> FsFuture<u32> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Control device.
+    fn ioctl (&self, _req: &Request, _ino: u64, _fh: u64, _flags: IoctlFlags, _cmd: u32, _arg: u64, _in_data: &[u8], _out_size: u32) -> FsFuture<Ioctl> {
+        ready(Err(ENOSYS))
+    }
+
+    /// Poll for events of an open file. Resolves to the events that are ready.
+    fn poll (&self, _req: &Request, _ino: u64, _fh: u64, _ph: PollHandle, _events: u32, _flags: u32) -> FsFuture<u32> {
+        ready(Err(ENOSYS))
+    }
+
+    /// OS X only: Rename the volume.
+    #[cfg(target_os = "macos")]
+    fn setvolname (&self, _req: &Request, _name: &OsStr) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// OS X only (undocumented)
+    #[cfg(target_os = "macos")]
+    fn exchange (&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _options: u64) -> FsFuture<()> {
+        ready(Err(ENOSYS))
+    }
+
+    /// OS X only: Query extended times (bkuptime and crtime).
+    #[cfg(target_os = "macos")]
+    fn getxtimes (&self, _req: &Request, _ino: u64) -> FsFuture<XTimes> {
+        ready(Err(ENOSYS))
+    }
+}
+
+/// Function that sends the reply for the result of an operation
+type ReplyFn<T> = Box<dyn FnOnce(Result<T, c_int>) + Send>;
+
+/// Future that completes an operation by passing the result of the filesystem's
+/// future to a reply function
+struct Replying<T> {
+    /// Future returned by the filesystem
+    future: FsFuture<T>,
+    /// Function that sends the reply, taken when the future completes
+    reply: Option<ReplyFn<T>>,
+}
+
+impl<T> Future for Replying<T> {
+    type Output = ();
+
+    fn poll (mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                let reply = self.reply.take().expect("future polled after completion");
+                reply(result);
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Driver for an asynchronous filesystem. It implements `Filesystem`, so it can
+/// be mounted and run by a session. Every operation is started by calling the
+/// asynchronous filesystem and the returned future is spawned on the executor,
+/// which sends the reply when the future completes. Since the session loop doesn't
+/// wait for operations to complete, a single session thread is sufficient.
+#[derive(Debug)]
+pub struct AsyncDriver<FS: AsyncFilesystem, E: Executor> {
+    /// The asynchronous filesystem
+    filesystem: Arc<FS>,
+    /// Executor to run futures on
+    executor: Arc<E>,
+}
+
+impl<FS: AsyncFilesystem, E: Executor> AsyncDriver<FS, E> {
+    /// Create a new driver for the given filesystem that runs futures on the given executor
+    pub fn new (filesystem: FS, executor: E) -> AsyncDriver<FS, E> {
+        AsyncDriver { filesystem: Arc::new(filesystem), executor: Arc::new(executor) }
+    }
+
+    /// Spawn the given future on the executor and pass its result to the given
+    /// reply function when it completes
+    fn spawn<T: 'static, F: FnOnce(Result<T, c_int>) + Send + 'static> (&self, future: FsFuture<T>, reply: F) {
+        self.executor.spawn(Box::pin(Replying { future: future, reply: Some(Box::new(reply)) }));
+    }
+
+    /// Spawn the given future and reply with an empty reply
+    fn spawn_empty (&self, future: FsFuture<()>, reply: ReplyEmpty) {
+        self.spawn(future, move |result| match result {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    /// Spawn the given future and reply with an entry
+    fn spawn_entry (&self, future: FsFuture<Entry>, reply: ReplyEntry) {
+        self.spawn(future, move |result| match result {
+            Ok(entry) => reply.entry(&entry.ttl, &entry.attr, entry.generation),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    /// Spawn the given future and reply with attributes
+    fn spawn_attr (&self, future: FsFuture<Attr>, reply: ReplyAttr) {
+        self.spawn(future, move |result| match result {
+            Ok(attr) => reply.attr(&attr.ttl, &attr.attr),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    /// Spawn the given future and reply with data
+    fn spawn_data (&self, future: FsFuture<Vec<u8>>, reply: ReplyData) {
+        self.spawn(future, move |result| match result {
+            Ok(data) => reply.data_owned(data),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    /// Spawn the given future and reply with an opened file handle
+    fn spawn_opened (&self, future: FsFuture<Opened>, reply: ReplyOpen) {
+        self.spawn(future, move |result| match result {
+            Ok(opened) => reply.opened(opened.fh, opened.flags),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    /// Spawn the given future and reply with xattr data or size
+    fn spawn_xattr (&self, future: FsFuture<Xattr>, reply: ReplyXattr) {
+        self.spawn(future, move |result| match result {
+            Ok(Xattr::Size(size)) => reply.size(size),
+            Ok(Xattr::Data(data)) => reply.data(&data),
+            Err(err) => reply.error(err),
+        });
+    }
+}
+
+impl<FS: AsyncFilesystem, E: Executor> Clone for AsyncDriver<FS, E> {
+    fn clone (&self) -> AsyncDriver<FS, E> {
+        AsyncDriver { filesystem: self.filesystem.clone(), executor: self.executor.clone() }
+    }
+}
+
+impl<FS: AsyncFilesystem, E: Executor> Filesystem for AsyncDriver<FS, E> {
+    fn init (&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.filesystem.init(req, config)
+    }
+
+    fn destroy (&mut self, req: &Request) {
+        self.filesystem.destroy(req)
+    }
+
+    fn lookup (&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let future = self.filesystem.lookup(req, parent, name);
+        self.spawn_entry(future, reply);
+    }
+
+    fn forget (&mut self, req: &Request, ino: u64, nlookup: u64) {
+        self.filesystem.forget(req, ino, nlookup)
+    }
+
+    fn batch_forget (&mut self, req: &Request, nodes: &[(u64, u64)]) {
+        self.filesystem.batch_forget(req, nodes)
+    }
+
+    fn getattr (&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let future = self.filesystem.getattr(req, ino);
+        self.spawn_attr(future, reply);
+    }
+
+    fn setattr (&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<Timespec>, fh: Option<u64>, kill_suidgid: bool, crtime: Option<Timespec>, chgtime: Option<Timespec>, bkuptime: Option<Timespec>, flags: Option<u32>, reply: ReplyAttr) {
+        let future = self.filesystem.setattr(req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, kill_suidgid, crtime, chgtime, bkuptime, flags);
+        self.spawn_attr(future, reply);
+    }
+
+    fn readlink (&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        let future = self.filesystem.readlink(req, ino);
+        self.spawn_data(future, reply);
+    }
+
+    fn mknod (&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32, reply: ReplyEntry) {
+        let future = self.filesystem.mknod(req, parent, name, mode, rdev);
+        self.spawn_entry(future, reply);
+    }
+
+    fn mkdir (&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let future = self.filesystem.mkdir(req, parent, name, mode);
+        self.spawn_entry(future, reply);
+    }
+
+    fn unlink (&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let future = self.filesystem.unlink(req, parent, name);
+        self.spawn_empty(future, reply);
+    }
+
+    fn rmdir (&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let future = self.filesystem.rmdir(req, parent, name);
+        self.spawn_empty(future, reply);
+    }
+
+    fn symlink (&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let future = self.filesystem.symlink(req, parent, name, link);
+        self.spawn_entry(future, reply);
+    }
+
+    fn rename (&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: RenameFlags, reply: ReplyEmpty) {
+        let future = self.filesystem.rename(req, parent, name, newparent, newname, flags);
+        self.spawn_empty(future, reply);
+    }
+
+    fn link (&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let future = self.filesystem.link(req, ino, newparent, newname);
+        self.spawn_entry(future, reply);
+    }
+
+    fn open (&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let future = self.filesystem.open(req, ino, flags);
+        self.spawn_opened(future, reply);
+    }
+
+    fn read (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+        let future = self.filesystem.read(req, ino, fh, offset, size);
+        self.spawn_data(future, reply);
+    }
+
+    fn write (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], write_flags: WriteFlags, lock_owner: Option<u64>, reply: ReplyWrite) {
+        let future = self.filesystem.write(req, ino, fh, offset, data, write_flags, lock_owner);
+        self.spawn(future, move |result| match result {
+            Ok(size) => reply.written(size),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn flush (&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let future = self.filesystem.flush(req, ino, fh, lock_owner);
+        self.spawn_empty(future, reply);
+    }
+
+    fn release (&mut self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+        let future = self.filesystem.release(req, ino, fh, flags, lock_owner, flush);
+        self.spawn_empty(future, reply);
+    }
+
+    fn fsync (&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let future = self.filesystem.fsync(req, ino, fh, datasync);
+        self.spawn_empty(future, reply);
+    }
+
+    fn opendir (&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let future = self.filesystem.opendir(req, ino, flags);
+        self.spawn_opened(future, reply);
+    }
+
+    fn readdir (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectory) {
+        let future = self.filesystem.readdir(req, ino, fh, offset);
+        self.spawn(future, move |result| match result {
+            Ok(entries) => {
+                for entry in entries {
+                    if reply.add(entry.ino, entry.offset, entry.kind, &entry.name) { break; }
+                }
+                reply.ok();
+            },
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn readdirplus (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectoryPlus) {
+        let future = self.filesystem.readdirplus(req, ino, fh, offset);
+        self.spawn(future, move |result| match result {
+            Ok(entries) => {
+                for entry in entries {
+                    let e = &entry.entry;
+                    if reply.add(e.attr.ino, entry.offset, &entry.name, &e.ttl, &e.attr, e.generation) { break; }
+                }
+                reply.ok();
+            },
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn releasedir (&mut self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        let future = self.filesystem.releasedir(req, ino, fh, flags);
+        self.spawn_empty(future, reply);
+    }
+
+    fn fsyncdir (&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let future = self.filesystem.fsyncdir(req, ino, fh, datasync);
+        self.spawn_empty(future, reply);
+    }
+
+    fn statfs (&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        let future = self.filesystem.statfs(req, ino);
+        self.spawn(future, move |result| match result {
+            Ok(st) => reply.statfs(st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn setxattr (&mut self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32, reply: ReplyEmpty) {
+        let future = self.filesystem.setxattr(req, ino, name, value, flags, position);
+        self.spawn_empty(future, reply);
+    }
+
+    fn getxattr (&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let future = self.filesystem.getxattr(req, ino, name, size);
+        self.spawn_xattr(future, reply);
+    }
+
+    fn listxattr (&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let future = self.filesystem.listxattr(req, ino, size);
+        self.spawn_xattr(future, reply);
+    }
+
+    fn removexattr (&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let future = self.filesystem.removexattr(req, ino, name);
+        self.spawn_empty(future, reply);
+    }
+
+    fn access (&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let future = self.filesystem.access(req, ino, mask);
+        self.spawn_empty(future, reply);
+    }
+
+    fn create (&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let future = self.filesystem.create(req, parent, name, mode, flags);
+        self.spawn(future, move |result| match result {
+            Ok(created) => reply.created(&created.entry.ttl, &created.entry.attr, created.entry.generation, created.fh, created.flags),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn getlk (&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, reply: ReplyLock) {
+        let future = self.filesystem.getlk(req, ino, fh, lock_owner, start, end, typ, pid);
+        self.spawn(future, move |result| match result {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn setlk (&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        let future = self.filesystem.setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep);
+        self.spawn_empty(future, reply);
+    }
+
+    fn bmap (&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        let future = self.filesystem.bmap(req, ino, blocksize, idx);
+        self.spawn(future, move |result| match result {
+            Ok(block) => reply.bmap(block),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn fallocate (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, length: u64, mode: FallocateFlags, reply: ReplyEmpty) {
+        let future = self.filesystem.fallocate(req, ino, fh, offset, length, mode);
+        self.spawn_empty(future, reply);
+    }
+
+    fn lseek (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, whence: c_int, reply: ReplyLseek) {
+        let future = self.filesystem.lseek(req, ino, fh, offset, whence);
+        self.spawn(future, move |result| match result {
+            Ok(offset) => reply.offset(offset),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn copy_file_range (&mut self, req: &Request, ino_in: u64, fh_in: u64, offset_in: u64, ino_out: u64, fh_out: u64, offset_out: u64, len: u64, flags: u64, reply: ReplyWrite) {
+        let future = self.filesystem.copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags);
+        self.spawn(future, move |result| match result {
+            Ok(size) => reply.written(size),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn ioctl (&mut self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, in_data: &[u8], out_size: u32, reply: ReplyIoctl) {
+        let future = self.filesystem.ioctl(req, ino, fh, flags, cmd, arg, in_data, out_size);
+        self.spawn(future, move |result| match result {
+            Ok(Ioctl::Data(result, data)) => reply.ioctl(result, &data),
+            Ok(Ioctl::Retry(in_iovs, out_iovs)) => reply.retry(&in_iovs, &out_iovs),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    fn poll (&mut self, req: &Request, ino: u64, fh: u64, ph: PollHandle, events: u32, flags: u32, reply: ReplyPoll) {
+        let future = self.filesystem.poll(req, ino, fh, ph, events, flags);
+        self.spawn(future, move |result| match result {
+            Ok(revents) => reply.poll(revents),
+            Err(err) => reply.error(err),
+        });
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname (&mut self, req: &Request, name: &OsStr, reply: ReplyEmpty) {
+        let future = self.filesystem.setvolname(req, name);
+        self.spawn_empty(future, reply);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange (&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64, reply: ReplyEmpty) {
+        let future = self.filesystem.exchange(req, parent, name, newparent, newname, options);
+        self.spawn_empty(future, reply);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes (&mut self, req: &Request, ino: u64, reply: ReplyXTimes) {
+        let future = self.filesystem.getxtimes(req, ino);
+        self.spawn(future, move |result| match result {
+            Ok(xtimes) => reply.xtimes(xtimes.bkuptime, xtimes.crtime),
+            Err(err) => reply.error(err),
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::mpsc::{channel, Sender};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::future::Future;
+    use std::thread::{self, Thread};
+    use libc::{EIO, ENOENT, ENOSYS};
+    use time::Timespec;
+    use {FileAttr, FileType, MockKernel, Request};
+    use super::{AsyncDriver, AsyncFilesystem, Entry, FsFuture, Replying, ready};
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake (self: Arc<Self>) { }
+    }
+
+    /// Waker that unparks the thread that polls the future
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake (self: Arc<Self>) { self.0.unpark(); }
+    }
+
+    /// Executor that polls every future on its own thread
+    fn thread_executor (mut future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        thread::spawn(move || {
+            let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+            let mut cx = Context::from_waker(&waker);
+            while future.as_mut().poll(&mut cx).is_pending() {
+                thread::park();
+            }
+        });
+    }
+
+    /// Asynchronous filesystem with a single file named "hello"
+    struct HelloFS;
+
+    impl AsyncFilesystem for HelloFS {
+        fn lookup (&self, _req: &Request, parent: u64, name: &OsStr) -> FsFuture<Entry> {
+            if parent != 1 || name != "hello" {
+                return ready(Err(ENOENT));
+            }
+            let ts = Timespec::new(0, 0);
+            let attr = FileAttr { ino: 2, size: 5, blocks: 1, atime: ts, mtime: ts, ctime: ts, crtime: ts, kind: FileType::RegularFile,
+                                  perm: 0o644, nlink: 1, uid: 0, gid: 0, rdev: 0, flags: 0, blksize: 512 };
+            ready(Ok(Entry { ttl: Timespec::new(1, 0), attr: attr, generation: 0 }))
+        }
+
+        fn read (&self, _req: &Request, ino: u64, _fh: u64, offset: u64, size: u32) -> FsFuture<Vec<u8>> {
+            match ino {
+                2 => ready(Ok(b"hello"[offset as usize..][..size as usize].to_vec())),
+                _ => ready(Err(EIO)),
+            }
+        }
+    }
+
+    fn replying (future: FsFuture<u32>, tx: Sender<Result<u32, i32>>) -> Replying<u32> {
+        Replying { future: future, reply: Some(Box::new(move |result| tx.send(result).unwrap())) }
+    }
+
+    #[test]
+    fn replying_future () {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let (tx, rx) = channel();
+        let mut future = replying(ready(Ok(42)), tx.clone());
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(()));
+        assert_eq!(rx.recv().unwrap(), Ok(42));
+        let mut future = replying(ready(Err(EIO)), tx);
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(()));
+        assert_eq!(rx.recv().unwrap(), Err(EIO));
+    }
+
+    #[test]
+    fn driver () {
+        let mut kernel = MockKernel::new(AsyncDriver::new(HelloFS, thread_executor)).unwrap();
+        assert_eq!(kernel.lookup(1, "hello").unwrap().ino, 2);
+        assert_eq!(kernel.lookup(1, "nonexistent").unwrap_err(), ENOENT);
+        assert_eq!(kernel.read(2, 0, 1, 3).unwrap(), b"ell");
+        assert_eq!(kernel.read(3, 0, 0, 3).unwrap_err(), EIO);
+        // Not implemented by the asynchronous filesystem
+        assert_eq!(kernel.getattr(2).unwrap_err(), ENOSYS);
+    }
+}
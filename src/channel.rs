@@ -2,17 +2,35 @@
 //! Raw communication channel to the FUSE kernel driver.
 //!
 
-use std::io;
-use std::ffi::{CString, CStr, OsStr};
+use std::{fs, io};
+#[cfg(feature = "libfuse")]
+use std::ffi::{CString, OsString};
+#[cfg(all(not(target_os = "linux"), feature = "libfuse"))]
+use std::ffi::CStr;
+#[cfg(feature = "libfuse")]
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::{PathBuf, Path};
-use libc::{self, c_int, c_void, size_t};
+use std::sync::{Arc, RwLock};
+use libc::{self, c_char, c_int, c_void, size_t, EBADF};
+#[cfg(feature = "libfuse")]
 use fuse::{fuse_args, fuse_mount_compat25};
-use reply::ReplySender;
+#[cfg(target_os = "linux")]
+use mount;
+#[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+use mount::Helper;
+use fuse::fuse_out_header;
+use mount_options::{MountOptions, UnmountFlags};
+use reply::{self, ReplySender};
+use splice::{self, Pipe};
+
+/// Max number of buffers that can be sent in a single write (UIO_MAXIOV)
+const MAX_IOVECS: usize = 1024;
 
 /// Helper function to provide options as a fuse_args struct
 /// (which contains an argc count and an argv pointer)
-fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T> (options: &[&OsStr], f: F) -> T {
+#[cfg(feature = "libfuse")]
+fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T> (options: &[OsString], f: F) -> T {
     let mut args = vec![CString::new("rust-fuse").unwrap()];
     args.extend(options.iter().map(|s| CString::new(s.as_bytes()).unwrap()));
     let argptrs: Vec<_> = args.iter().map(|s| s.as_ptr()).collect();
@@ -24,31 +42,134 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T> (options: &[&OsStr], f: F) -> T
 pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
+    /// Id of the connection in /sys/fs/fuse/connections (if known)
+    connection: Option<u32>,
+    /// True if the mount point is unmounted when the channel is dropped (false for clones)
+    mounted: bool,
+    /// The fusermount helper that unmounts when its socket is closed (auto_unmount)
+    #[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+    helper: Option<Helper>,
+    /// True while the fd is open, shared with guarded senders
+    open: Arc<RwLock<bool>>,
 }
 
 impl Channel {
+    /// Create a channel for the given fd, which is closed when the channel is dropped.
+    /// If mounted is set, the mount point is unmounted as well.
+    fn from_fd (mountpoint: PathBuf, fd: c_int, connection: Option<u32>, mounted: bool) -> Channel {
+        Channel {
+            mountpoint: mountpoint,
+            fd: fd,
+            connection: connection,
+            mounted: mounted,
+            #[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+            helper: None,
+            open: Arc::new(RwLock::new(true)),
+        }
+    }
+
     /// Create a new communication channel to the kernel driver by mounting the
     /// given path. The kernel driver will delegate filesystem operations of
     /// the given path to the channel. If the channel is dropped, the path is
     /// unmounted.
-    pub fn new (mountpoint: &Path, options: &[&OsStr]) -> io::Result<Channel> {
-        let mountpoint = try!(mountpoint.canonicalize());
-        with_fuse_args(options, |args| {
-            let mnt = try!(CString::new(mountpoint.as_os_str().as_bytes()));
+    #[cfg(feature = "libfuse")]
+    pub fn new (mountpoint: &Path, options: &MountOptions) -> io::Result<Channel> {
+        let mountpoint = mountpoint.canonicalize()?;
+        with_fuse_args(&options.to_args(), |args| {
+            let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
             let fd = unsafe { fuse_mount_compat25(mnt.as_ptr(), args) };
             if fd < 0 {
                 Err(io::Error::last_os_error())
             } else {
-                Ok(Channel { mountpoint: mountpoint, fd: fd })
+                let connection = lookup_connection(&mountpoint);
+                Ok(Channel::from_fd(mountpoint, fd, connection, true))
             }
         })
     }
 
+    /// Create a new communication channel to the kernel driver by mounting the
+    /// given path. The kernel driver will delegate filesystem operations of
+    /// the given path to the channel. If the channel is dropped, the path is
+    /// unmounted.
+    #[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+    pub fn new (mountpoint: &Path, options: &MountOptions) -> io::Result<Channel> {
+        let mountpoint = mountpoint.canonicalize()?;
+        let mount = mount::mount(&mountpoint, options)?;
+        let connection = lookup_connection(&mountpoint);
+        let mut ch = Channel::from_fd(mountpoint, mount.fd, connection, true);
+        ch.helper = mount.helper;
+        Ok(ch)
+    }
+
+    /// Create a new communication channel to the kernel driver by mounting the
+    /// given path. Mounting without libfuse is only supported on Linux.
+    #[cfg(all(not(target_os = "linux"), not(feature = "libfuse")))]
+    pub fn new (_mountpoint: &Path, _options: &MountOptions) -> io::Result<Channel> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Create a pair of connected channels that aren't backed by the kernel driver.
+    /// Messages sent to one channel are received by the other one (one message per
+    /// receive, like with the kernel driver), which allows emulating the kernel
+    /// driver in-process, e.g. for testing.
+    pub fn pair () -> io::Result<(Channel, Channel)> {
+        let mut fds = [0; 2];
+        if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((Channel::from_fd(PathBuf::new(), fds[0], None, false), Channel::from_fd(PathBuf::new(), fds[1], None, false)))
+    }
+
     /// Return path of the mounted filesystem
     pub fn mountpoint (&self) -> &Path {
         &self.mountpoint
     }
 
+    /// Return id of the connection to the kernel driver, which is the device number
+    /// of the mounted filesystem. Only known on Linux.
+    pub fn connection (&self) -> Option<u32> {
+        self.connection
+    }
+
+    /// Create a clone of this channel that uses a duplicate of the fd. Clones can be
+    /// used to receive requests in other threads. Unlike the original channel, a
+    /// clone doesn't unmount the mount point when dropped.
+    pub fn try_clone (&self) -> io::Result<Channel> {
+        let fd = unsafe { libc::dup(self.fd) };
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Channel::from_fd(self.mountpoint.clone(), fd, self.connection, false))
+        }
+    }
+
+    /// Create a clone of this channel with a new fd for the same connection to the
+    /// kernel driver. Unlike with a duplicate fd, the kernel queues requests that were
+    /// received using the new fd separately, so replies to them must be sent using
+    /// the new fd as well. Only supported on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn clone_connection (&self) -> io::Result<Channel> {
+        use fuse::FUSE_DEV_IOC_CLONE;
+        let fd = unsafe { libc::open(b"/dev/fuse\0".as_ptr() as *const c_char, libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut master_fd = self.fd as u32;
+        if unsafe { libc::ioctl(fd, FUSE_DEV_IOC_CLONE as _, &mut master_fd) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+        Ok(Channel::from_fd(self.mountpoint.clone(), fd, self.connection, false))
+    }
+
+    /// Create a clone of this channel with a new fd for the same connection to the
+    /// kernel driver. Only supported on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn clone_connection (&self) -> io::Result<Channel> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Receives data up to the capacity of the given buffer (can block).
     pub fn receive (&self, buffer: &mut Vec<u8>) -> io::Result<()> {
         let rc = unsafe { libc::read(self.fd, buffer.as_ptr() as *mut c_void, buffer.capacity() as size_t) };
@@ -60,6 +181,13 @@ impl Channel {
         }
     }
 
+    /// Receives the next request using the given pipe (can block). The request is read
+    /// into the given buffer, except for the payload of a large write request, which is
+    /// left in the pipe. Returns the size of the payload that was left in the pipe.
+    pub fn receive_splice (&self, pipe: &Pipe, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        splice::receive(self.fd, pipe, buffer)
+    }
+
     /// Returns a sender object for this channel. The sender object can be
     /// used to send to the channel. Multiple sender objects can be used
     /// and they can safely be sent to other threads.
@@ -68,7 +196,14 @@ impl Channel {
         // a sender by using the same fd and use it in other threads. Only
         // the channel closes the fd when dropped. If any sender is used after
         // dropping the channel, it'll return an EBADF error.
-        ChannelSender { fd: self.fd }
+        ChannelSender { fd: self.fd, splice: None }
+    }
+
+    /// Returns a guarded sender object for this channel. Unlike a plain sender, it
+    /// can safely be kept after the channel was dropped, since it then fails with
+    /// EBADF instead of writing to whatever the fd number refers to by then.
+    pub fn guarded_sender (&self) -> GuardedSender {
+        GuardedSender { sender: self.sender(), open: self.open.clone() }
     }
 }
 
@@ -77,20 +212,44 @@ impl Drop for Channel {
         // TODO: send ioctl FUSEDEVIOCSETDAEMONDEAD on OS X before closing the fd
         // Close the communication channel to the kernel driver
         // (closing it before unnmount prevents sync unmount deadlock)
+        // Waits for guarded senders that are sending at the moment
+        *self.open.write().unwrap() = false;
         unsafe { libc::close(self.fd); }
         // Unmount this channel's mount point
-        let _ = unmount(&self.mountpoint);
+        if self.mounted {
+            let _ = unmount(&self.mountpoint);
+            info!("Unmounted {}", self.mountpoint.display());
+        }
+        // Let the auto_unmount helper exit
+        #[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+        {
+            if let Some(helper) = self.helper.take() {
+                helper.finish();
+            }
+        }
     }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct ChannelSender {
     fd: c_int,
+    /// If set, replies from files are spliced (with SPLICE_F_MOVE if true)
+    splice: Option<bool>,
 }
 
 impl ChannelSender {
+    /// Returns a sender that splices replies from files instead of copying them.
+    /// If splice_move is set, the kernel may move pages instead of copying them.
+    pub fn with_splice (self, splice_move: bool) -> ChannelSender {
+        ChannelSender { fd: self.fd, splice: Some(splice_move) }
+    }
+
     /// Send all data in the slice of slice of bytes in a single write (can block).
     pub fn send (&self, buffer: &[&[u8]]) -> io::Result<()> {
+        // A single write can only gather a limited number of buffers
+        if buffer.len() > MAX_IOVECS {
+            return self.send(&[&buffer.concat()]);
+        }
         let iovecs: Vec<_> = buffer.iter().map(|d| {
             libc::iovec { iov_base: d.as_ptr() as *mut c_void, iov_len: d.len() as size_t }
         }).collect();
@@ -103,16 +262,74 @@ impl ChannelSender {
     }
 }
 
+/// Sender that knows whether its channel is still open
+#[derive(Clone, Debug)]
+pub struct GuardedSender {
+    sender: ChannelSender,
+    /// True while the fd of the channel is open
+    open: Arc<RwLock<bool>>,
+}
+
+impl GuardedSender {
+    /// Send all data in the slice of slice of bytes in a single write (can block).
+    /// Fails with EBADF if the channel was dropped.
+    pub fn send (&self, buffer: &[&[u8]]) -> io::Result<()> {
+        // Keep the channel from closing the fd while sending
+        let open = self.open.read().unwrap();
+        if !*open {
+            return Err(io::Error::from_raw_os_error(EBADF));
+        }
+        self.sender.send(buffer)
+    }
+}
+
 impl ReplySender for ChannelSender {
     fn send(&self, data: &[&[u8]]) {
         if let Err(err) = ChannelSender::send(self, data) {
             error!("Failed to send FUSE reply: {}", err);
         }
     }
+
+    fn send_fd (&self, header: fuse_out_header, fd: RawFd, offset: u64, len: usize) -> io::Result<()> {
+        if let Some(splice_move) = self.splice {
+            match splice::send_fd(self.fd, &header, fd, offset, len, splice_move) {
+                Ok(true) => return Ok(()),
+                Ok(false) => (),
+                Err(err) => { error!("Failed to send FUSE reply: {}", err); return Ok(()); },
+            }
+        }
+        reply::send_fd_copy(self, header, fd, offset, len)
+    }
 }
 
 /// Unmount an arbitrary mount point
 pub fn unmount (mountpoint: &Path) -> io::Result<()> {
+    unmount_with(mountpoint, UnmountFlags::default())
+}
+
+/// Unmount an arbitrary mount point using the given flags. If we're not privileged,
+/// fusermount is used, which supports lazy but not forced unmounting.
+#[cfg(target_os = "linux")]
+pub fn unmount_with (mountpoint: &Path, flags: UnmountFlags) -> io::Result<()> {
+    let connection = mounted_connection(mountpoint);
+    mount::unmount(mountpoint, flags)?;
+    // Don't rely on fusermount's exit status, make sure the mount is gone
+    if connection.is_some() && mounted_connection(mountpoint) == connection {
+        return Err(io::Error::from_raw_os_error(libc::EBUSY));
+    }
+    Ok(())
+}
+
+/// Unmount an arbitrary mount point using the given flags. Unmounting without
+/// libfuse is only supported on Linux.
+#[cfg(all(not(target_os = "linux"), not(feature = "libfuse")))]
+pub fn unmount_with (_mountpoint: &Path, _flags: UnmountFlags) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+/// Unmount an arbitrary mount point using the given flags
+#[cfg(all(not(target_os = "linux"), feature = "libfuse"))]
+pub fn unmount_with (mountpoint: &Path, flags: UnmountFlags) -> io::Result<()> {
     // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
     // it attempts to call realpath, which in turn calls into the filesystem. So
     // if the filesystem returns an error, the unmount does not take place, with
@@ -122,27 +339,23 @@ pub fn unmount (mountpoint: &Path) -> io::Result<()> {
 
     #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
               target_os = "openbsd", target_os = "bitrig", target_os = "netbsd"))] #[inline]
-    fn libc_umount (mnt: &CStr) -> c_int { unsafe { libc::unmount(mnt.as_ptr(), 0) } }
+    fn libc_umount (mnt: &CStr, flags: UnmountFlags) -> c_int {
+        // Lazy unmounting isn't supported
+        let umount_flags = if flags.force { libc::MNT_FORCE } else { 0 };
+        unsafe { libc::unmount(mnt.as_ptr(), umount_flags) }
+    }
 
     #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "dragonfly",
                   target_os = "openbsd", target_os = "bitrig", target_os = "netbsd")))] #[inline]
-    fn libc_umount (mnt: &CStr) -> c_int {
-        use fuse::fuse_unmount_compat22;
-        use std::io::ErrorKind::PermissionDenied;
-
-        let rc = unsafe { libc::umount(mnt.as_ptr()) };
-        if rc < 0 && io::Error::last_os_error().kind() == PermissionDenied {
-            // Linux always returns EPERM for non-root users.  We have to let the
-            // library go through the setuid-root "fusermount -u" to unmount.
-            unsafe { fuse_unmount_compat22(mnt.as_ptr()); }
-            0
-        } else {
-            rc
-        }
+    fn libc_umount (mnt: &CStr, flags: UnmountFlags) -> c_int {
+        let mut umount_flags = 0;
+        if flags.lazy { umount_flags |= libc::MNT_DETACH; }
+        if flags.force { umount_flags |= libc::MNT_FORCE; }
+        unsafe { libc::umount2(mnt.as_ptr(), umount_flags) }
     }
 
-    let mnt = try!(CString::new(mountpoint.as_os_str().as_bytes()));
-    let rc = libc_umount(&mnt);
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let rc = libc_umount(&mnt, flags);
     if rc < 0 {
         Err(io::Error::last_os_error())
     } else {
@@ -150,19 +363,118 @@ pub fn unmount (mountpoint: &Path) -> io::Result<()> {
     }
 }
 
+/// Abort the connection to the kernel driver with the given id. All outstanding and
+/// future requests fail with ECONNABORTED and the session loop ends. This works even
+/// if the filesystem doesn't respond anymore. Only supported on Linux.
+#[cfg(target_os = "linux")]
+pub fn abort_connection (connection: u32) -> io::Result<()> {
+    fs::write(format!("/sys/fs/fuse/connections/{}/abort", connection), b"1")
+}
+
+/// Abort the connection to the kernel driver with the given id. Only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn abort_connection (_connection: u32) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+/// Look up the id of the connection of the filesystem mounted at the given path.
+/// This reads the mount table instead of calling stat on the mount point, since
+/// that would call into the filesystem, which doesn't run yet.
+#[cfg(target_os = "linux")]
+fn lookup_connection (mountpoint: &Path) -> Option<u32> {
+    let connection = mounted_connection(mountpoint);
+    if connection.is_none() {
+        warn!("Failed to look up FUSE connection of {}", mountpoint.display());
+    }
+    connection
+}
+
+/// Returns the id of the connection of the filesystem that is currently mounted at
+/// the given path (if any)
+#[cfg(target_os = "linux")]
+fn mounted_connection (mountpoint: &Path) -> Option<u32> {
+    use std::os::unix::ffi::OsStrExt;
+    fs::read("/proc/self/mountinfo").ok()
+        .and_then(|mountinfo| parse_mountinfo(&mountinfo, mountpoint.as_os_str().as_bytes()))
+}
+
+/// Look up the id of the connection of the filesystem mounted at the given path.
+/// Only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+fn lookup_connection (_mountpoint: &Path) -> Option<u32> {
+    None
+}
+
+/// Find the device number of the given mount point in the given mount table (as
+/// in /proc/self/mountinfo). If the path is mounted multiple times, the most recent
+/// mount wins. The device number is encoded like the kernel does internally,
+/// which is how connections are named in /sys/fs/fuse/connections.
+#[cfg(target_os = "linux")]
+fn parse_mountinfo (mountinfo: &[u8], mountpoint: &[u8]) -> Option<u32> {
+    mountinfo.split(|&b| b == b'\n').rev().find_map(|line| {
+        // Fields are mount id, parent id, major:minor, root, mount point, ...
+        let fields: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+        if fields.len() < 5 || unescape_mountinfo(fields[4]) != mountpoint {
+            return None;
+        }
+        let dev = String::from_utf8_lossy(fields[2]).into_owned();
+        let mut parts = dev.splitn(2, ':').map(|n| n.parse::<u32>().ok());
+        match (parts.next(), parts.next()) {
+            (Some(Some(major)), Some(Some(minor))) => Some((major << 20) | minor),
+            _ => None,
+        }
+    })
+}
+
+/// Unescape a path in the mount table (space, tab, newline and backslash are
+/// escaped as octal numbers)
+#[cfg(target_os = "linux")]
+fn unescape_mountinfo (field: &[u8]) -> Vec<u8> {
+    let mut path = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] == b'\\' && i + 4 <= field.len() && field[i+1..i+4].iter().all(|b| b'0' <= *b && *b <= b'7') {
+            path.push(field[i+1..i+4].iter().fold(0u8, |n, b| (n << 3) | (b - b'0')));
+            i += 4;
+        } else {
+            path.push(field[i]);
+            i += 1;
+        }
+    }
+    path
+}
+
 
 #[cfg(test)]
 mod test {
+    #[cfg(feature = "libfuse")]
     use super::with_fuse_args;
-    use std::ffi::{CStr, OsStr};
+    #[cfg(feature = "libfuse")]
+    use std::ffi::{CStr, OsString};
+    #[cfg(target_os = "linux")]
+    use super::parse_mountinfo;
 
+    #[cfg(feature = "libfuse")]
     #[test]
     fn fuse_args () {
-        with_fuse_args(&[OsStr::new("foo"), OsStr::new("bar")], |args| {
+        with_fuse_args(&[OsString::from("foo"), OsString::from("bar")], |args| {
             assert_eq!(args.argc, 3);
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(0)).to_bytes() }, b"rust-fuse");
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(1)).to_bytes() }, b"foo");
             assert_eq!(unsafe { CStr::from_ptr(*args.argv.offset(2)).to_bytes() }, b"bar");
         });
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mountinfo () {
+        let mountinfo = b"22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                          40 22 0:45 / /mnt/my\\040fs rw,nosuid shared:20 - fuse.hello hello rw\n\
+                          41 22 0:46 / /mnt/other rw shared:21 - fuse.hello hello rw\n\
+                          42 40 0:47 / /mnt/my\\040fs rw shared:22 - fuse.hello hello rw\n";
+        assert_eq!(parse_mountinfo(mountinfo, b"/mnt/other"), Some(46));
+        assert_eq!(parse_mountinfo(mountinfo, b"/mnt/my fs"), Some(47));
+        assert_eq!(parse_mountinfo(mountinfo, b"/"), Some((8 << 20) | 1));
+        assert_eq!(parse_mountinfo(mountinfo, b"/mnt"), None);
+    }
 }
@@ -0,0 +1,134 @@
+//!
+//! Typed flags of filesystem operations. Flags wrap the raw bits the kernel
+//! sent, so bits that aren't known here are kept and can still be inspected.
+//!
+
+use std::ops::{BitAnd, BitOr};
+
+/// Define a type for a set of flags with the given known flags
+macro_rules! flags {
+    ($(#[$attr:meta])* pub struct $name:ident: $ty:ty {
+        $($(#[$flag_attr:meta])* const $flag:ident = $value:expr;)*
+    }) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+        pub struct $name($ty);
+
+        impl $name {
+            $($(#[$flag_attr])* pub const $flag: $name = $name($value);)*
+
+            /// Create flags from the given raw bits
+            pub fn from_bits (bits: $ty) -> $name {
+                $name(bits)
+            }
+
+            /// Returns the raw bits
+            pub fn bits (&self) -> $ty {
+                self.0
+            }
+
+            /// Returns true if no flag is set
+            pub fn is_empty (&self) -> bool {
+                self.0 == 0
+            }
+
+            /// Returns true if all of the given flags are set
+            pub fn contains (&self, other: $name) -> bool {
+                self.0 & other.0 == other.0
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = $name;
+            fn bitor (self, other: $name) -> $name {
+                $name(self.0 | other.0)
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = $name;
+            fn bitand (self, other: $name) -> $name {
+                $name(self.0 & other.0)
+            }
+        }
+    }
+}
+
+flags! {
+    /// Mode of a fallocate operation (see fallocate(2)). Without any flag, the
+    /// given range is allocated and the file size is extended if needed.
+    pub struct FallocateFlags: u32 {
+        /// Don't change the file size even if the range exceeds it
+        const KEEP_SIZE = 0x01;
+        /// Deallocate the range (always used together with KEEP_SIZE)
+        const PUNCH_HOLE = 0x02;
+        /// Remove the range and shift the following data down
+        const COLLAPSE_RANGE = 0x08;
+        /// Zero the range and allocate it
+        const ZERO_RANGE = 0x10;
+        /// Insert a hole at the range and shift the following data up
+        const INSERT_RANGE = 0x20;
+        /// Unshare blocks of the range that are shared with other files
+        const UNSHARE_RANGE = 0x40;
+    }
+}
+
+flags! {
+    /// Flags of a rename operation (see renameat2(2)). Filesystems that don't
+    /// support a flag should reply with EINVAL.
+    pub struct RenameFlags: u32 {
+        /// Don't overwrite the new name, fail with EEXIST if it exists
+        const NOREPLACE = 0x01;
+        /// Atomically exchange the old and new name (both must exist)
+        const EXCHANGE = 0x02;
+        /// Leave a whiteout object at the old name (used by overlay filesystems)
+        const WHITEOUT = 0x04;
+    }
+}
+
+flags! {
+    /// Flags of an ioctl operation
+    pub struct IoctlFlags: u32 {
+        /// 32-bit compat ioctl on a 64-bit machine
+        const COMPAT = 0x01;
+        /// Not restricted to well-formed ioctls, replying with a retry is allowed
+        const UNRESTRICTED = 0x02;
+        /// The calling process is 32-bit, so addresses of its memory are 32-bit
+        const IS_32BIT = 0x08;
+        /// The ioctl is done on a directory
+        const DIR = 0x10;
+        /// x32 compat ioctl on a 64-bit machine (64-bit time_t)
+        const COMPAT_X32 = 0x20;
+    }
+}
+
+flags! {
+    /// Flags of a write operation
+    pub struct WriteFlags: u32 {
+        /// Delayed write from the page cache (writeback cache mode). The file
+        /// handle is guessed by the kernel and may belong to any open of the file.
+        const CACHE = 0x01;
+        /// The lock owner of the writing process is known
+        const LOCKOWNER = 0x02;
+        /// Clear the set-user-id and set-group-id bits of the file
+        const KILL_SUIDGID = 0x04;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::FallocateFlags;
+
+    #[test]
+    fn flags () {
+        let mode = FallocateFlags::from_bits(0x03);
+        assert_eq!(mode, FallocateFlags::KEEP_SIZE | FallocateFlags::PUNCH_HOLE);
+        assert!(mode.contains(FallocateFlags::PUNCH_HOLE));
+        assert!(!mode.contains(FallocateFlags::PUNCH_HOLE | FallocateFlags::ZERO_RANGE));
+        assert_eq!(mode & FallocateFlags::ZERO_RANGE, FallocateFlags::default());
+        assert!(FallocateFlags::default().is_empty());
+        // Unknown bits are kept
+        assert_eq!(FallocateFlags::from_bits(0x8001).bits(), 0x8001);
+    }
+}
@@ -1,15 +1,17 @@
 //!
-//! FUSE native interface declarations (as of libosxfuse-2.5.5).
+//! FUSE native interface declarations (as of Linux FUSE ABI 7.36 and macFUSE ABI 7.19).
 //!
 
 #![allow(non_camel_case_types, missing_docs, dead_code)]
 
+#[cfg(feature = "libfuse")]
 use libc::{c_int, c_char};
 
 //
 // FUSE arguments (see fuse_opt.h for details)
 //
 
+#[cfg(feature = "libfuse")]
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_args {
@@ -22,6 +24,7 @@ pub struct fuse_args {
 // FUSE common (see fuse_common_compat.h for details)
 //
 
+#[cfg(feature = "libfuse")]
 extern "system" {
     pub fn fuse_mount_compat25 (mountpoint: *const c_char, args: *const fuse_args) -> c_int;
     pub fn fuse_unmount_compat22 (mountpoint: *const c_char);
@@ -32,9 +35,24 @@ extern "system" {
 //
 
 pub const FUSE_KERNEL_VERSION: u32 = 7;
-pub const FUSE_KERNEL_MINOR_VERSION: u32 = 8;
+#[cfg(not(target_os = "macos"))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 36;
+#[cfg(target_os = "macos")]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
 pub const FUSE_ROOT_ID: u64 = 1;
 
+// Device ioctl to clone a connection to a new /dev/fuse fd (Linux only)
+#[cfg(all(target_os = "linux", not(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips", target_arch = "mips64", target_arch = "sparc64"))))]
+pub const FUSE_DEV_IOC_CLONE: u32 = 0x8004e500;         // _IOR(229, 0, uint32_t)
+#[cfg(all(target_os = "linux", any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips", target_arch = "mips64", target_arch = "sparc64")))]
+pub const FUSE_DEV_IOC_CLONE: u32 = 0x4004e500;         // _IOR(229, 0, uint32_t)
+
+// Sizes of structs sent or expected by kernels with an older minor ABI version
+pub const FUSE_COMPAT_MKNOD_IN_SIZE: usize = 8;         // before 7.12
+pub const FUSE_COMPAT_CREATE_IN_SIZE: usize = 8;        // before 7.12 (a fuse_open_in)
+pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;     // before 7.23
+pub const FUSE_COMPAT_INIT_IN_SIZE: usize = 16;         // before 7.36
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_attr {
@@ -58,6 +76,11 @@ pub struct fuse_attr {
     pub rdev: u32,
     #[cfg(target_os = "macos")]
     pub flags: u32,             // OS X only, see chflags(2)
+    pub blksize: u32,
+    #[cfg(not(target_os = "macos"))]
+    pub flags: u32,             // Linux only, FUSE_ATTR_* flags
+    #[cfg(target_os = "macos")]
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -93,6 +116,12 @@ pub mod consts {
     pub const FATTR_ATIME: u32              = 1 << 4;
     pub const FATTR_MTIME: u32              = 1 << 5;
     pub const FATTR_FH: u32                 = 1 << 6;
+    pub const FATTR_ATIME_NOW: u32          = 1 << 7;
+    pub const FATTR_MTIME_NOW: u32          = 1 << 8;
+    pub const FATTR_LOCKOWNER: u32          = 1 << 9;
+    pub const FATTR_CTIME: u32              = 1 << 10;
+    #[cfg(not(target_os = "macos"))]
+    pub const FATTR_KILL_SUIDGID: u32       = 1 << 11;  // Linux only
     #[cfg(target_os = "macos")]
     pub const FATTR_CRTIME: u32             = 1 << 28;  // OS X only
     #[cfg(target_os = "macos")]
@@ -105,6 +134,13 @@ pub mod consts {
     // Flags returned by the open request
     pub const FOPEN_DIRECT_IO: u32          = 1 << 0;   // bypass page cache for this open file
     pub const FOPEN_KEEP_CACHE: u32         = 1 << 1;   // don't invalidate the data cache on open
+    pub const FOPEN_NONSEEKABLE: u32        = 1 << 2;   // the file is not seekable
+    #[cfg(not(target_os = "macos"))]
+    pub const FOPEN_CACHE_DIR: u32          = 1 << 3;   // allow caching this directory (Linux only)
+    #[cfg(not(target_os = "macos"))]
+    pub const FOPEN_STREAM: u32             = 1 << 4;   // the file is stream-like, no file position (Linux only)
+    #[cfg(not(target_os = "macos"))]
+    pub const FOPEN_NOFLUSH: u32            = 1 << 5;   // don't flush data cache on close (Linux only)
     #[cfg(target_os = "macos")]
     pub const FOPEN_PURGE_ATTR: u32         = 1 << 30;  // OS X only
     #[cfg(target_os = "macos")]
@@ -118,6 +154,34 @@ pub mod consts {
     pub const FUSE_EXPORT_SUPPORT: u32      = 1 << 4;
     pub const FUSE_BIG_WRITES: u32          = 1 << 5;
     pub const FUSE_DONT_MASK: u32           = 1 << 6;
+    pub const FUSE_SPLICE_WRITE: u32        = 1 << 7;
+    pub const FUSE_SPLICE_MOVE: u32         = 1 << 8;
+    pub const FUSE_SPLICE_READ: u32         = 1 << 9;
+    pub const FUSE_FLOCK_LOCKS: u32         = 1 << 10;
+    pub const FUSE_HAS_IOCTL_DIR: u32       = 1 << 11;
+    pub const FUSE_AUTO_INVAL_DATA: u32     = 1 << 12;
+    pub const FUSE_DO_READDIRPLUS: u32      = 1 << 13;
+    pub const FUSE_READDIRPLUS_AUTO: u32    = 1 << 14;
+    pub const FUSE_ASYNC_DIO: u32           = 1 << 15;
+    pub const FUSE_WRITEBACK_CACHE: u32     = 1 << 16;
+    pub const FUSE_NO_OPEN_SUPPORT: u32     = 1 << 17;
+    pub const FUSE_PARALLEL_DIROPS: u32     = 1 << 18;
+    pub const FUSE_HANDLE_KILLPRIV: u32     = 1 << 19;
+    pub const FUSE_POSIX_ACL: u32           = 1 << 20;
+    pub const FUSE_ABORT_ERROR: u32         = 1 << 21;
+    pub const FUSE_MAX_PAGES: u32           = 1 << 22;
+    pub const FUSE_CACHE_SYMLINKS: u32      = 1 << 23;
+    pub const FUSE_NO_OPENDIR_SUPPORT: u32  = 1 << 24;
+    pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25;
+    pub const FUSE_MAP_ALIGNMENT: u32       = 1 << 26;
+    pub const FUSE_SUBMOUNTS: u32           = 1 << 27;
+    pub const FUSE_HANDLE_KILLPRIV_V2: u32  = 1 << 28;
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SETXATTR_EXT: u32        = 1 << 29;  // Linux only
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_INIT_EXT: u32            = 1 << 30;  // Linux only
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_INIT_RESERVED: u32       = 1 << 31;  // Linux only
     #[cfg(target_os = "macos")]
     pub const FUSE_CASE_INSENSITIVE: u32    = 1 << 29;  // OS X only
     #[cfg(target_os = "macos")]
@@ -125,8 +189,55 @@ pub mod consts {
     #[cfg(target_os = "macos")]
     pub const FUSE_XTIMES: u32              = 1 << 31;  // OS X only
 
+    // Init request/reply flags2 (with FUSE_INIT_EXT)
+    #[cfg(not(target_os = "macos"))]
+    pub const FUSE_SECURITY_CTX: u32        = 1 << 0;   // Linux only
+
+    // CUSE init request/reply flags
+    pub const CUSE_UNRESTRICTED_IOCTL: u32  = 1 << 0;   // use unrestricted ioctl
+
     // Release flags
     pub const FUSE_RELEASE_FLUSH: u32       = 1 << 0;
+    pub const FUSE_RELEASE_FLOCK_UNLOCK: u32 = 1 << 1;
+
+    // Getattr flags
+    pub const FUSE_GETATTR_FH: u32          = 1 << 0;
+
+    // Lock flags
+    pub const FUSE_LK_FLOCK: u32            = 1 << 0;
+
+    // Write flags
+    pub const FUSE_WRITE_CACHE: u32         = 1 << 0;   // delayed write from page cache, file handle is guessed
+    pub const FUSE_WRITE_LOCKOWNER: u32     = 1 << 1;   // lock_owner field is valid
+    pub const FUSE_WRITE_KILL_SUIDGID: u32  = 1 << 2;   // kill suid and sgid bits
+
+    // Read flags
+    pub const FUSE_READ_LOCKOWNER: u32      = 1 << 1;
+
+    // IOCTL flags
+    pub const FUSE_IOCTL_COMPAT: u32        = 1 << 0;   // 32bit compat ioctl on 64bit machine
+    pub const FUSE_IOCTL_UNRESTRICTED: u32  = 1 << 1;   // not restricted to well-formed ioctls, retry allowed
+    pub const FUSE_IOCTL_RETRY: u32         = 1 << 2;   // retry with new iovecs
+    pub const FUSE_IOCTL_32BIT: u32         = 1 << 3;   // 32bit ioctl
+    pub const FUSE_IOCTL_DIR: u32           = 1 << 4;   // is a directory
+    pub const FUSE_IOCTL_COMPAT_X32: u32    = 1 << 5;   // x32 compat ioctl on 64bit machine (64bit time_t)
+
+    pub const FUSE_IOCTL_MAX_IOV: u32       = 256;      // maximum of in_iovecs + out_iovecs
+
+    // Poll flags
+    pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0;  // request poll notify
+
+    // Fsync flags
+    pub const FUSE_FSYNC_FDATASYNC: u32     = 1 << 0;   // sync data only, not metadata
+
+    // Attr flags
+    pub const FUSE_ATTR_SUBMOUNT: u32       = 1 << 0;   // object is a submount root
+
+    // Open flags
+    pub const FUSE_OPEN_KILL_SUIDGID: u32   = 1 << 0;   // kill suid and sgid if executable
+
+    // Setxattr flags
+    pub const FUSE_SETXATTR_ACL_KILL_SGID: u32 = 1 << 0; // clear SGID when system.posix_acl_access is set
 }
 
 #[repr(C)]
@@ -168,12 +279,25 @@ pub enum fuse_opcode {
     FUSE_INTERRUPT = 36,
     FUSE_BMAP = 37,
     FUSE_DESTROY = 38,
+    FUSE_IOCTL = 39,
+    FUSE_POLL = 40,
+    FUSE_NOTIFY_REPLY = 41,     // no reply
+    FUSE_BATCH_FORGET = 42,     // no reply
+    FUSE_FALLOCATE = 43,
+    FUSE_READDIRPLUS = 44,
+    FUSE_RENAME2 = 45,
+    FUSE_LSEEK = 46,
+    FUSE_COPY_FILE_RANGE = 47,
+    FUSE_SETUPMAPPING = 48,
+    FUSE_REMOVEMAPPING = 49,
+    FUSE_SYNCFS = 50,
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,       // OS X only
     #[cfg(target_os = "macos")]
     FUSE_GETXTIMES = 62,        // OS X only
     #[cfg(target_os = "macos")]
     FUSE_EXCHANGE = 63,         // OS X only
+    CUSE_INIT = 4096,
 }
 
 // FIXME: Hopefully Rust will once have a more convenient way of converting primitive to enum
@@ -216,17 +340,41 @@ impl fuse_opcode {
             36 => Some(fuse_opcode::FUSE_INTERRUPT),
             37 => Some(fuse_opcode::FUSE_BMAP),
             38 => Some(fuse_opcode::FUSE_DESTROY),
+            39 => Some(fuse_opcode::FUSE_IOCTL),
+            40 => Some(fuse_opcode::FUSE_POLL),
+            41 => Some(fuse_opcode::FUSE_NOTIFY_REPLY),
+            42 => Some(fuse_opcode::FUSE_BATCH_FORGET),
+            43 => Some(fuse_opcode::FUSE_FALLOCATE),
+            44 => Some(fuse_opcode::FUSE_READDIRPLUS),
+            45 => Some(fuse_opcode::FUSE_RENAME2),
+            46 => Some(fuse_opcode::FUSE_LSEEK),
+            47 => Some(fuse_opcode::FUSE_COPY_FILE_RANGE),
+            48 => Some(fuse_opcode::FUSE_SETUPMAPPING),
+            49 => Some(fuse_opcode::FUSE_REMOVEMAPPING),
+            50 => Some(fuse_opcode::FUSE_SYNCFS),
             #[cfg(target_os = "macos")]
             61 => Some(fuse_opcode::FUSE_SETVOLNAME),
             #[cfg(target_os = "macos")]
             62 => Some(fuse_opcode::FUSE_GETXTIMES),
             #[cfg(target_os = "macos")]
             63 => Some(fuse_opcode::FUSE_EXCHANGE),
+            4096 => Some(fuse_opcode::CUSE_INIT),
             _ => None,
         }
     }
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub enum fuse_notify_code {
+    FUSE_NOTIFY_POLL = 1,
+    FUSE_NOTIFY_INVAL_INODE = 2,
+    FUSE_NOTIFY_INVAL_ENTRY = 3,
+    FUSE_NOTIFY_STORE = 4,
+    FUSE_NOTIFY_RETRIEVE = 5,
+    FUSE_NOTIFY_DELETE = 6,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_entry_out {
@@ -245,6 +393,28 @@ pub struct fuse_forget_in {
     pub nlookup: u64,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_forget_one {
+    pub nodeid: u64,
+    pub nlookup: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_batch_forget_in {
+    pub count: u32,
+    pub dummy: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_getattr_in {
+    pub getattr_flags: u32,
+    pub dummy: u32,
+    pub fh: u64,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_attr_out {
@@ -269,13 +439,15 @@ pub struct fuse_getxtimes_out { // OS X only
 pub struct fuse_mknod_in {
     pub mode: u32,
     pub rdev: u32,
+    pub umask: u32,
+    pub padding: u32,
 }
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_mkdir_in {
     pub mode: u32,
-    pub padding: u32,
+    pub umask: u32,
 }
 
 #[repr(C)]
@@ -284,6 +456,14 @@ pub struct fuse_rename_in {
     pub newdir: u64,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_rename2_in {
+    pub newdir: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
 #[cfg(target_os = "macos")]
 #[repr(C)]
 #[derive(Debug)]
@@ -306,13 +486,13 @@ pub struct fuse_setattr_in {
     pub padding: u32,
     pub fh: u64,
     pub size: u64,
-    pub unused1: u64,
+    pub lock_owner: u64,
     pub atime: i64,
     pub mtime: i64,
-    pub unused2: u64,
+    pub ctime: i64,
     pub atimensec: i32,
     pub mtimensec: i32,
-    pub unused3: u32,
+    pub ctimensec: i32,
     pub mode: u32,
     pub unused4: u32,
     pub uid: u32,
@@ -337,8 +517,17 @@ pub struct fuse_setattr_in {
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_open_in {
+    pub flags: u32,
+    pub open_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_create_in {
     pub flags: u32,
     pub mode: u32,
+    pub umask: u32,
+    pub open_flags: u32,
 }
 
 #[repr(C)]
@@ -373,6 +562,9 @@ pub struct fuse_read_in {
     pub fh: u64,
     pub offset: u64,
     pub size: u32,
+    pub read_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
     pub padding: u32,
 }
 
@@ -383,6 +575,9 @@ pub struct fuse_write_in {
     pub offset: u64,
     pub size: u32,
     pub write_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -441,6 +636,8 @@ pub struct fuse_lk_in {
     pub fh: u64,
     pub owner: u64,
     pub lk: fuse_file_lock,
+    pub lk_flags: u32,
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -463,6 +660,8 @@ pub struct fuse_init_in {
     pub minor: u32,
     pub max_readahead: u32,
     pub flags: u32,
+    pub flags2: u32,
+    pub unused: [u32; 11],
 }
 
 #[repr(C)]
@@ -472,8 +671,14 @@ pub struct fuse_init_out {
     pub minor: u32,
     pub max_readahead: u32,
     pub flags: u32,
-    pub unused: u32,
+    pub max_background: u16,
+    pub congestion_threshold: u16,
     pub max_write: u32,
+    pub time_gran: u32,
+    pub max_pages: u16,
+    pub map_alignment: u16,
+    pub flags2: u32,
+    pub unused: [u32; 7],
 }
 
 #[repr(C)]
@@ -496,6 +701,65 @@ pub struct fuse_bmap_out {
     pub block: u64,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_ioctl_in {
+    pub fh: u64,
+    pub flags: u32,
+    pub cmd: u32,
+    pub arg: u64,
+    pub in_size: u32,
+    pub out_size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_ioctl_iovec {
+    pub base: u64,
+    pub len: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_ioctl_out {
+    pub result: i32,
+    pub flags: u32,
+    pub in_iovs: u32,
+    pub out_iovs: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_poll_in {
+    pub fh: u64,
+    pub kh: u64,
+    pub flags: u32,
+    pub events: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_poll_out {
+    pub revents: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_notify_poll_wakeup_out {
+    pub kh: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_fallocate_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub mode: u32,
+    pub padding: u32,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_in_header {
@@ -526,3 +790,92 @@ pub struct fuse_dirent {
     pub typ: u32,
     // followed by name of namelen bytes
 }
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_direntplus {
+    pub entry_out: fuse_entry_out,
+    pub dirent: fuse_dirent,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_notify_inval_inode_out {
+    pub ino: u64,
+    pub off: i64,
+    pub len: i64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_notify_inval_entry_out {
+    pub parent: u64,
+    pub namelen: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_notify_delete_out {
+    pub parent: u64,
+    pub child: u64,
+    pub namelen: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_notify_store_out {
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_notify_retrieve_out {
+    pub notify_unique: u64,
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_notify_retrieve_in {
+    pub dummy1: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub dummy2: u32,
+    pub dummy3: u64,
+    pub dummy4: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_lseek_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub whence: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_lseek_out {
+    pub offset: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_copy_file_range_in {
+    pub fh_in: u64,
+    pub off_in: u64,
+    pub nodeid_out: u64,
+    pub fh_out: u64,
+    pub off_out: u64,
+    pub len: u64,
+    pub flags: u64,
+}
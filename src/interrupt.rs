@@ -0,0 +1,208 @@
+//!
+//! Interrupting requests. If a process that waits for a filesystem operation
+//! receives a signal, the kernel sends an interrupt request that refers to the
+//! original request. The session keeps track of requests that haven't been
+//! replied yet, so that it can mark them as interrupted. Filesystem code can
+//! check this using an interrupt token and abort the operation with EINTR.
+//!
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use fuse::fuse_out_header;
+use reply::ReplySender;
+
+/// Token that tells whether a request was interrupted by the kernel. Tokens
+/// can be cloned and sent to other threads together with the reply.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptToken {
+    /// Create a new token that is not interrupted
+    pub fn new () -> InterruptToken {
+        InterruptToken::default()
+    }
+
+    /// Returns true if the kernel interrupted the request. The operation should
+    /// be aborted as soon as possible and replied with EINTR.
+    pub fn is_interrupted (&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Mark the request as interrupted
+    fn interrupt (&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Bookkeeping of in-flight requests and interrupts
+#[derive(Debug, Default)]
+struct State {
+    /// Tokens of requests that haven't been replied yet, by unique id
+    inflight: HashMap<u64, InterruptToken>,
+    /// Interrupts that arrived before the request they refer to, as pairs of
+    /// the interrupt's unique id and the interrupted request's unique id
+    pending: Vec<(u64, u64)>,
+}
+
+/// In-flight requests of a session. This can be cloned and shared with reply
+/// senders in other threads.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupts {
+    state: Arc<Mutex<State>>,
+    /// Signalled when the last in-flight request was replied
+    idle: Arc<Condvar>,
+}
+
+impl Interrupts {
+    /// Create a new, empty set of in-flight requests
+    pub fn new () -> Interrupts {
+        Interrupts::default()
+    }
+
+    /// Register the request with the given unique id as in-flight
+    pub fn register (&self, unique: u64, token: &InterruptToken) {
+        self.state.lock().unwrap().inflight.insert(unique, token.clone());
+    }
+
+    /// Remove the request with the given unique id from the in-flight requests
+    pub fn unregister (&self, unique: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.inflight.remove(&unique);
+        if state.inflight.is_empty() {
+            self.idle.notify_all();
+        }
+    }
+
+    /// Wait until all in-flight requests are replied, but not longer than the given
+    /// timeout. Returns false if requests are still in-flight after the timeout.
+    pub fn wait_idle (&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.idle.wait_timeout_while(state, timeout, |state| !state.inflight.is_empty()).unwrap();
+        state.inflight.is_empty()
+    }
+
+    /// Handle an interrupt request (with unique id `unique`) for the request with
+    /// unique id `target`. If the request is in-flight, it's marked as interrupted.
+    /// Otherwise it either was replied already or didn't arrive yet, so the
+    /// interrupt is remembered until the next request arrives. Returns true if
+    /// the request was found.
+    pub fn interrupt (&self, unique: u64, target: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(token) = state.inflight.get(&target) {
+            token.interrupt();
+            return true;
+        }
+        state.pending.push((unique, target));
+        false
+    }
+
+    /// Check pending interrupts for a newly arrived request. If an interrupt for
+    /// it is pending, the given token is marked as interrupted. Otherwise, the
+    /// oldest pending interrupt refers to a request that was replied already. Its
+    /// unique id is returned and it should be replied with EAGAIN, which makes the
+    /// kernel re-send the interrupt if the request is still waiting.
+    pub fn check (&self, unique: u64, token: &InterruptToken) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        match state.pending.iter().position(|&(_, target)| target == unique) {
+            Some(pos) => {
+                state.pending.remove(pos);
+                token.interrupt();
+                None
+            },
+            None if !state.pending.is_empty() => Some(state.pending.remove(0).0),
+            None => None,
+        }
+    }
+}
+
+/// Reply sender that removes its request from the in-flight requests when the
+/// reply is sent
+#[derive(Debug)]
+pub struct InFlightSender<S: ReplySender> {
+    /// Sender to send the reply with
+    sender: S,
+    /// Unique id of the request
+    unique: u64,
+    /// In-flight requests of the session
+    interrupts: Interrupts,
+}
+
+impl<S: ReplySender> InFlightSender<S> {
+    /// Register the request with the given unique id as in-flight and create a
+    /// sender for its reply
+    pub fn new (sender: S, unique: u64, token: &InterruptToken, interrupts: &Interrupts) -> InFlightSender<S> {
+        interrupts.register(unique, token);
+        InFlightSender { sender: sender, unique: unique, interrupts: interrupts.clone() }
+    }
+}
+
+impl<S: ReplySender> ReplySender for InFlightSender<S> {
+    fn send (&self, data: &[&[u8]]) {
+        // Unregister before sending, since the kernel may reuse the unique id
+        // as soon as it got the reply
+        self.interrupts.unregister(self.unique);
+        self.sender.send(data);
+    }
+
+    fn send_fd (&self, header: fuse_out_header, fd: RawFd, offset: u64, len: usize) -> io::Result<()> {
+        self.interrupts.unregister(self.unique);
+        self.sender.send_fd(header, fd, offset, len)
+    }
+
+    fn send_owned (&self, header: fuse_out_header, data: Vec<u8>) {
+        self.interrupts.unregister(self.unique);
+        self.sender.send_owned(header, data)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+    use super::{Interrupts, InterruptToken};
+
+    #[test]
+    fn interrupt_inflight () {
+        let interrupts = Interrupts::new();
+        let token = InterruptToken::new();
+        interrupts.register(1, &token);
+        assert!(!token.is_interrupted());
+        assert!(interrupts.interrupt(2, 1));
+        assert!(token.is_interrupted());
+        interrupts.unregister(1);
+        assert!(!interrupts.interrupt(3, 1));
+    }
+
+    #[test]
+    fn wait_idle () {
+        let interrupts = Interrupts::new();
+        assert!(interrupts.wait_idle(Duration::from_secs(0)));
+        interrupts.register(1, &InterruptToken::new());
+        assert!(!interrupts.wait_idle(Duration::from_millis(10)));
+        let other = interrupts.clone();
+        let thread = thread::spawn(move || other.unregister(1));
+        assert!(interrupts.wait_idle(Duration::from_secs(10)));
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn interrupt_pending () {
+        let interrupts = Interrupts::new();
+        assert!(!interrupts.interrupt(2, 1));
+        assert!(!interrupts.interrupt(4, 3));
+        let token = InterruptToken::new();
+        assert_eq!(interrupts.check(3, &token), None);
+        assert!(token.is_interrupted());
+        let token = InterruptToken::new();
+        assert_eq!(interrupts.check(5, &token), Some(2));
+        assert!(!token.is_interrupted());
+        assert_eq!(interrupts.check(6, &token), None);
+    }
+}
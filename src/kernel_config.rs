@@ -0,0 +1,182 @@
+//!
+//! Configuration of the connection to the kernel driver. During the init
+//! operation, the kernel tells us which capabilities it supports and the
+//! filesystem can decide which of them it wants to use.
+//!
+
+use fuse::consts::*;
+use session::MAX_WRITE_SIZE;
+
+/// We generally support async reads, lookups of . and .. and writes larger than 4k.
+/// On Linux, we additionally allow requests larger than the kernel's default of 32 pages
+#[cfg(not(target_os = "macos"))]
+const DEFAULT_INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES | FUSE_MAX_PAGES;
+
+/// On OS X, we additionally support case insensitiveness, volume renames and xtimes
+#[cfg(target_os = "macos")]
+const DEFAULT_INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
+
+/// Splicing data is done by the session and doesn't need support by the kernel driver
+pub const SPLICE_FLAGS: u32 = FUSE_SPLICE_WRITE | FUSE_SPLICE_MOVE | FUSE_SPLICE_READ;
+
+/// On Linux, splicing is always offered in addition to the kernel's capabilities
+#[cfg(target_os = "linux")]
+const SESSION_FLAGS: u32 = SPLICE_FLAGS;
+
+/// Splicing is only supported on Linux
+#[cfg(not(target_os = "linux"))]
+const SESSION_FLAGS: u32 = 0;
+
+/// The smallest max write size the kernel accepts
+const MIN_WRITE_SIZE: u32 = 4096;
+
+/// Configuration of the kernel connection.
+///
+/// This is passed to the init method of the filesystem and can be used to
+/// inspect the capabilities and settings offered by the kernel and to decide
+/// which of them should be used. Capabilities are given as bitmasks of the
+/// `FUSE_*` init flags in `fuse::consts`.
+#[derive(Debug)]
+pub struct KernelConfig {
+    /// FUSE protocol major version supported by the kernel
+    major: u32,
+    /// FUSE protocol minor version supported by the kernel
+    minor: u32,
+    /// Capabilities offered by the kernel
+    capabilities: u32,
+    /// Capabilities requested by the filesystem
+    requested: u32,
+    /// Max readahead size offered by the kernel
+    kernel_max_readahead: u32,
+    /// Max readahead size requested by the filesystem
+    max_readahead: u32,
+    /// Max size of write requests requested by the filesystem
+    max_write: u32,
+}
+
+impl KernelConfig {
+    /// Create a new kernel configuration from the values the kernel sent
+    /// with the init request
+    pub fn new (major: u32, minor: u32, capabilities: u32, max_readahead: u32) -> KernelConfig {
+        KernelConfig {
+            major: major,
+            minor: minor,
+            capabilities: capabilities | SESSION_FLAGS,
+            requested: capabilities & DEFAULT_INIT_FLAGS,
+            kernel_max_readahead: max_readahead,
+            max_readahead: max_readahead,
+            max_write: MAX_WRITE_SIZE as u32,
+        }
+    }
+
+    /// Returns the FUSE protocol version (major, minor) supported by the kernel
+    pub fn abi_version (&self) -> (u32, u32) {
+        (self.major, self.minor)
+    }
+
+    /// Returns the capabilities offered by the kernel. On Linux, this includes splice
+    /// mode (FUSE_SPLICE_*), which is provided by the session: with FUSE_SPLICE_READ,
+    /// the payload of large write requests is kept in a pipe (see `WriteBuf`), with
+    /// FUSE_SPLICE_WRITE, data replies from files are spliced and FUSE_SPLICE_MOVE
+    /// additionally lets the kernel move pages instead of copying them.
+    pub fn capabilities (&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Returns the capabilities that are currently requested. By default, async
+    /// reads, export support, big writes and (on Linux) max pages are requested if
+    /// offered by the kernel.
+    pub fn requested_capabilities (&self) -> u32 {
+        self.requested
+    }
+
+    /// Request the given capabilities. If the kernel doesn't offer all of them,
+    /// nothing is changed and the unsupported capabilities are returned as error.
+    pub fn add_capabilities (&mut self, capabilities: u32) -> Result<(), u32> {
+        let unsupported = capabilities & !self.capabilities;
+        if unsupported != 0 {
+            return Err(unsupported);
+        }
+        self.requested |= capabilities;
+        Ok(())
+    }
+
+    /// Stop requesting the given capabilities
+    pub fn remove_capabilities (&mut self, capabilities: u32) {
+        self.requested &= !capabilities;
+    }
+
+    /// Returns the max readahead size. Defaults to the size offered by the kernel.
+    pub fn max_readahead (&self) -> u32 {
+        self.max_readahead
+    }
+
+    /// Set the max readahead size. The value must not exceed the size offered by
+    /// the kernel, otherwise nothing is changed and the max value is returned as
+    /// error. On success, the previous value is returned.
+    pub fn set_max_readahead (&mut self, value: u32) -> Result<u32, u32> {
+        if value > self.kernel_max_readahead {
+            return Err(self.kernel_max_readahead);
+        }
+        let previous = self.max_readahead;
+        self.max_readahead = value;
+        Ok(previous)
+    }
+
+    /// Set the max size of write requests. The value must be at least 4k and must
+    /// fit into the session's receive buffer, otherwise nothing is changed and the
+    /// nearest valid value is returned as error. On success, the previous value is
+    /// returned.
+    pub fn set_max_write (&mut self, value: u32) -> Result<u32, u32> {
+        if value < MIN_WRITE_SIZE {
+            return Err(MIN_WRITE_SIZE);
+        }
+        if value > MAX_WRITE_SIZE as u32 {
+            return Err(MAX_WRITE_SIZE as u32);
+        }
+        let previous = self.max_write;
+        self.max_write = value;
+        Ok(previous)
+    }
+
+    /// Returns the max size of write requests. Defaults to the max size that fits
+    /// into the session's receive buffer.
+    pub fn max_write (&self) -> u32 {
+        self.max_write
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::KernelConfig;
+    use fuse::consts::*;
+
+    #[test]
+    fn default_capabilities () {
+        let config = KernelConfig::new(7, 8, FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_BIG_WRITES, 0x20000);
+        assert_eq!(config.abi_version(), (7, 8));
+        assert_eq!(config.requested_capabilities(), FUSE_ASYNC_READ | FUSE_BIG_WRITES);
+    }
+
+    #[test]
+    fn add_remove_capabilities () {
+        let mut config = KernelConfig::new(7, 8, FUSE_ASYNC_READ | FUSE_POSIX_LOCKS, 0x20000);
+        assert_eq!(config.add_capabilities(FUSE_POSIX_LOCKS | FUSE_DONT_MASK), Err(FUSE_DONT_MASK));
+        assert_eq!(config.requested_capabilities(), FUSE_ASYNC_READ);
+        assert_eq!(config.add_capabilities(FUSE_POSIX_LOCKS), Ok(()));
+        config.remove_capabilities(FUSE_ASYNC_READ);
+        assert_eq!(config.requested_capabilities(), FUSE_POSIX_LOCKS);
+    }
+
+    #[test]
+    fn max_sizes () {
+        let mut config = KernelConfig::new(7, 8, 0, 0x20000);
+        assert_eq!(config.set_max_readahead(0x40000), Err(0x20000));
+        assert_eq!(config.set_max_readahead(0x10000), Ok(0x20000));
+        assert_eq!(config.max_readahead(), 0x10000);
+        assert_eq!(config.set_max_write(512), Err(4096));
+        assert_eq!(config.set_max_write(0x10000), Ok(16*1024*1024));
+        assert_eq!(config.max_write(), 0x10000);
+    }
+}
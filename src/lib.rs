@@ -13,31 +13,56 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate time;
-extern crate thread_scoped;
 
 use std::convert::AsRef;
 use std::io;
 use std::ffi::OsStr;
 use std::path::Path;
-use libc::{c_int, ENOSYS};
+use std::sync::Arc;
+use libc::{c_int, EIO, ENOSYS};
 use time::Timespec;
 
+pub use async_fs::{AsyncFilesystem, AsyncDriver, Executor, FsFuture};
+pub use async_fs::{Entry, Attr, Opened, Created, Statfs, Lock, Xattr, DirEntry, DirEntryPlus, Ioctl};
+#[cfg(target_os = "macos")]
+pub use async_fs::XTimes;
 pub use fuse::FUSE_ROOT_ID;
 pub use fuse::consts;
+pub use interrupt::InterruptToken;
+pub use flags::{FallocateFlags, IoctlFlags, RenameFlags, WriteFlags};
+pub use kernel_config::KernelConfig;
+pub use mock_kernel::MockKernel;
+pub use mount_options::{MountOption, MountOptions, UnmountFlags};
+pub use notify::{Notifier, PollHandle};
 pub use reply::{Reply, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
-pub use reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
+pub use reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyLseek, ReplyDirectory};
+pub use reply::ReplyDirectoryPlus;
 pub use reply::ReplyXattr;
+pub use reply::{ReplyIoctl, ReplyPoll};
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use request::Request;
 pub use session::{Session, BackgroundSession};
+pub use shutdown::{ExitReason, Unmounter};
+pub use splice::WriteBuf;
 
 mod argument;
+mod async_fs;
 mod channel;
+mod flags;
 mod fuse;
+mod interrupt;
+mod kernel_config;
+mod mock_kernel;
+#[cfg(target_os = "linux")]
+mod mount;
+mod mount_options;
+mod notify;
 mod reply;
 mod request;
 mod session;
+mod shutdown;
+mod splice;
 
 /// File types
 #[derive(Clone, Copy, Debug, Hash, PartialEq)]
@@ -87,6 +112,17 @@ pub struct FileAttr {
     pub rdev: u32,
     /// Flags (OS X only, see chflags(2))
     pub flags: u32,
+    /// Preferred block size for I/O
+    pub blksize: u32,
+}
+
+/// Time to set a file time to (used by setattr)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeOrNow {
+    /// The given time
+    SpecificTime(Timespec),
+    /// The current time of the filesystem
+    Now,
 }
 
 /// Filesystem trait.
@@ -97,13 +133,17 @@ pub struct FileAttr {
 /// nothing.
 pub trait Filesystem {
     /// Initialize filesystem.
-    /// Called before any other filesystem method.
-    fn init (&mut self, _req: &Request) -> Result<(), c_int> {
+    /// Called before any other filesystem method. The kernel config can be used to
+    /// inspect the capabilities offered by the kernel and to choose which of them
+    /// should be used, e.g. FUSE_WRITEBACK_CACHE to let the kernel cache writes.
+    fn init (&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
         Ok(())
     }
 
     /// Clean up filesystem.
-    /// Called on filesystem exit.
+    /// Called on filesystem exit. If the session ends without the kernel sending
+    /// a destroy request (e.g. because the connection was aborted), it's called
+    /// after the session loop ended.
     fn destroy (&mut self, _req: &Request) {
     }
 
@@ -122,13 +162,27 @@ pub trait Filesystem {
     fn forget (&mut self, _req: &Request, _ino: u64, _nlookup: u64) {
     }
 
+    /// Forget about multiple inodes.
+    /// The kernel sends this instead of single forgets if it drops many inodes at once
+    /// (e.g. on cache pressure). The nodes are given as pairs of inode number and
+    /// nlookup. The default implementation calls forget for every inode.
+    fn batch_forget (&mut self, req: &Request, nodes: &[(u64, u64)]) {
+        for &(ino, nlookup) in nodes {
+            self.forget(req, ino, nlookup);
+        }
+    }
+
     /// Get file attributes.
     fn getattr (&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
     /// Set file attributes.
-    fn setattr (&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+    /// Only the given attributes should be changed. In writeback cache mode, the
+    /// kernel maintains the file times itself and sends them here, including ctime.
+    /// If kill_suidgid is set, the set-user-id and set-group-id bits should be
+    /// cleared as well (Linux only).
+    fn setattr (&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<Timespec>, _fh: Option<u64>, _kill_suidgid: bool, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
@@ -164,7 +218,9 @@ pub trait Filesystem {
     }
 
     /// Rename a file.
-    fn rename (&mut self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
+    /// The flags are given if renaming using renameat2(2) on Linux. If the filesystem
+    /// doesn't support a flag, it should reply with EINVAL.
+    fn rename (&mut self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: RenameFlags, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -201,11 +257,29 @@ pub trait Filesystem {
     /// exception to this is when the file has been opened in 'direct_io' mode, in
     /// which case the return value of the write system call will reflect the return
     /// value of this operation. fh will contain the value set by the open method, or
-    /// will be undefined if the open method didn't set any value.
-    fn write (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
+    /// will be undefined if the open method didn't set any value. In writeback cache
+    /// mode (FUSE_WRITEBACK_CACHE), writes from the page cache have the CACHE flag
+    /// set and fh may belong to any open of the file, even a read-only one.
+    /// lock_owner is only given if the kernel knows the writing process.
+    fn write (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _write_flags: WriteFlags, _lock_owner: Option<u64>, reply: ReplyWrite) {
         reply.error(ENOSYS);
     }
 
+    /// Write data from a buffer.
+    /// This is called for every write request. In splice mode (FUSE_SPLICE_READ), the
+    /// data of large writes is kept in a pipe and can be spliced to a file using
+    /// `WriteBuf::write_to` without copying it. By default, the data is copied to
+    /// memory if needed and passed to the write method.
+    fn write_buf (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &WriteBuf, write_flags: WriteFlags, lock_owner: Option<u64>, reply: ReplyWrite) {
+        match data.as_slice() {
+            Some(data) => self.write(req, ino, fh, offset, data, write_flags, lock_owner, reply),
+            None => match data.to_vec() {
+                Ok(data) => self.write(req, ino, fh, offset, &data, write_flags, lock_owner, reply),
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+            },
+        }
+    }
+
     /// Flush method.
     /// This is called on each close() of the opened file. Since file descriptors can
     /// be duplicated (dup, dup2, fork), for one open call there may be many flush
@@ -259,6 +333,16 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Read directory with attributes.
+    /// Like readdir, but every entry also carries its attributes, so that the kernel
+    /// doesn't need to look up each entry separately. Entries other than . and ..
+    /// increase the lookup count like a lookup does. This is only called if
+    /// FUSE_DO_READDIRPLUS is requested in init. If FUSE_READDIRPLUS_AUTO is
+    /// requested too, the kernel decides adaptively whether to use readdir or readdirplus.
+    fn readdirplus (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, reply: ReplyDirectoryPlus) {
+        reply.error(ENOSYS);
+    }
+
     /// Release an open directory.
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
@@ -351,6 +435,54 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Allocate or deallocate space of an open file.
+    /// The mode tells what to do with the given range (see fallocate(2)). Without any
+    /// flag, the range should be allocated so that later writes to it don't fail for
+    /// lack of space. If this method isn't implemented, the kernel fails fallocate
+    /// with EOPNOTSUPP and doesn't call it again.
+    fn fallocate (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _length: u64, _mode: FallocateFlags, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Find the next data or hole of an open file.
+    /// Whence is SEEK_DATA or SEEK_HOLE (other values are handled by the kernel). Reply
+    /// with the offset of the next data or hole at or after the given offset, or ENXIO
+    /// if there is none. If this method isn't implemented, the kernel falls back to
+    /// treating the whole file as data.
+    fn lseek (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _whence: c_int, reply: ReplyLseek) {
+        reply.error(ENOSYS);
+    }
+
+    /// Copy a range of data from one open file to another.
+    /// This allows copying on the server side (or by sharing blocks) instead of
+    /// passing all data through read and write. Reply with the number of bytes
+    /// copied, which may be less than requested. Flags are passed on from
+    /// copy_file_range(2). If this method isn't implemented, the kernel falls back
+    /// to read and write.
+    fn copy_file_range (&mut self, _req: &Request, _ino_in: u64, _fh_in: u64, _offset_in: u64, _ino_out: u64, _fh_out: u64, _offset_out: u64, _len: u64, _flags: u64, reply: ReplyWrite) {
+        reply.error(ENOSYS);
+    }
+
+    /// Control device.
+    /// The arg parameter is the argument of the ioctl call (e.g. the address of the
+    /// data in the calling process). For restricted ioctls, the kernel derives the
+    /// size of the input data and the max size of the output data from the command.
+    /// Unrestricted ioctls (e.g. by CUSE devices) don't encode sizes in the command,
+    /// so they can be replied with a retry that tells which memory of the calling
+    /// process to pass in and out. The ioctl is then sent again with that input data.
+    fn ioctl (&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: IoctlFlags, _cmd: u32, _arg: u64, _in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        reply.error(ENOSYS);
+    }
+
+    /// Poll for events of an open file.
+    /// Reply with the events (POLLIN, POLLOUT, ...) that are ready of the requested ones.
+    /// If flags contain FUSE_POLL_SCHEDULE_NOTIFY, the poll handle should be kept and
+    /// used to notify the kernel once an event becomes ready. If this method isn't
+    /// implemented, the kernel treats files as always ready for reading and writing.
+    fn poll (&mut self, _req: &Request, _ino: u64, _fh: u64, _ph: PollHandle, _events: u32, _flags: u32, reply: ReplyPoll) {
+        reply.error(ENOSYS);
+    }
+
     /// OS X only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
@@ -372,17 +504,430 @@ pub trait Filesystem {
     }
 }
 
+/// Concurrent filesystem trait.
+///
+/// Like `Filesystem`, but methods take `&self`, so a single filesystem can be used
+/// by multiple threads at once. A reference or an `Arc` of a concurrent filesystem
+/// implements `Filesystem` and can be run with `Session::run_mt`. See the
+/// `Filesystem` trait for a description of the methods.
+pub trait ConcurrentFilesystem: Sync + Send {
+    /// Initialize filesystem.
+    fn init (&self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    fn destroy (&self, _req: &Request) {
+    }
+
+    /// Look up a directory entry by name and get its attributes.
+    fn lookup (&self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+        reply.error(ENOSYS);
+    }
+
+    /// Forget about an inode.
+    fn forget (&self, _req: &Request, _ino: u64, _nlookup: u64) {
+    }
+
+    /// Forget about multiple inodes. The default implementation calls forget for
+    /// every inode.
+    fn batch_forget (&self, req: &Request, nodes: &[(u64, u64)]) {
+        for &(ino, nlookup) in nodes {
+            self.forget(req, ino, nlookup);
+        }
+    }
+
+    /// Get file attributes.
+    fn getattr (&self, _req: &Request, _ino: u64, reply: ReplyAttr) {
+        reply.error(ENOSYS);
+    }
+
+    /// Set file attributes.
+    fn setattr (&self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<Timespec>, _fh: Option<u64>, _kill_suidgid: bool, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        reply.error(ENOSYS);
+    }
+
+    /// Read symbolic link.
+    fn readlink (&self, _req: &Request, _ino: u64, reply: ReplyData) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create file node.
+    fn mknod (&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create a directory.
+    fn mkdir (&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove a file.
+    fn unlink (&self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove a directory.
+    fn rmdir (&self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create a symbolic link.
+    fn symlink (&self, _req: &Request, _parent: u64, _name: &OsStr, _link: &Path, reply: ReplyEntry) {
+        reply.error(ENOSYS);
+    }
+
+    /// Rename a file.
+    fn rename (&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _flags: RenameFlags, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create a hard link.
+    fn link (&self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) {
+        reply.error(ENOSYS);
+    }
+
+    /// Open a file.
+    fn open (&self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    /// Read data.
+    fn read (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _size: u32, reply: ReplyData) {
+        reply.error(ENOSYS);
+    }
+
+    /// Write data.
+    fn write (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _write_flags: WriteFlags, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        reply.error(ENOSYS);
+    }
+
+    /// Write data from a buffer.
+    fn write_buf (&self, req: &Request, ino: u64, fh: u64, offset: u64, data: &WriteBuf, write_flags: WriteFlags, lock_owner: Option<u64>, reply: ReplyWrite) {
+        match data.as_slice() {
+            Some(data) => self.write(req, ino, fh, offset, data, write_flags, lock_owner, reply),
+            None => match data.to_vec() {
+                Ok(data) => self.write(req, ino, fh, offset, &data, write_flags, lock_owner, reply),
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+            },
+        }
+    }
+
+    /// Flush method.
+    fn flush (&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Release an open file.
+    fn release (&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    /// Synchronize file contents.
+    fn fsync (&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Open a directory.
+    fn opendir (&self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    /// Read directory.
+    fn readdir (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, reply: ReplyDirectory) {
+        reply.error(ENOSYS);
+    }
+
+    /// Read directory with attributes.
+    fn readdirplus (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, reply: ReplyDirectoryPlus) {
+        reply.error(ENOSYS);
+    }
+
+    /// Release an open directory.
+    fn releasedir (&self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    /// Synchronize directory contents.
+    fn fsyncdir (&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Get file system statistics.
+    fn statfs (&self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+    }
+
+    /// Set an extended attribute.
+    fn setxattr (&self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Get an extended attribute.
+    fn getxattr (&self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+        reply.error(ENOSYS);
+    }
+
+    /// List extended attribute names.
+    fn listxattr (&self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {
+        reply.error(ENOSYS);
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr (&self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Check file access permissions.
+    fn access (&self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Create and open a file.
+    fn create (&self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32, reply: ReplyCreate) {
+        reply.error(ENOSYS);
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk (&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, reply: ReplyLock) {
+        reply.error(ENOSYS);
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    fn setlk (&self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Map block index within file to block index within device.
+    fn bmap (&self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
+        reply.error(ENOSYS);
+    }
+
+    /// Allocate or deallocate space of an open file.
+    fn fallocate (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _length: u64, _mode: FallocateFlags, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// Find the next data or hole of an open file.
+    fn lseek (&self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _whence: c_int, reply: ReplyLseek) {
+        reply.error(ENOSYS);
+    }
+
+    /// Copy a range of data from one open file to another.
+    fn copy_file_range (&self, _req: &Request, _ino_in: u64, _fh_in: u64, _offset_in: u64, _ino_out: u64, _fh_out: u64, _offset_out: u64, _len: u64, _flags: u64, reply: ReplyWrite) {
+        reply.error(ENOSYS);
+    }
+
+    /// Control device.
+    fn ioctl (&self, _req: &Request, _ino: u64, _fh: u64, _flags: IoctlFlags, _cmd: u32, _arg: u64, _in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        reply.error(ENOSYS);
+    }
+
+    /// Poll for events of an open file.
+    fn poll (&self, _req: &Request, _ino: u64, _fh: u64, _ph: PollHandle, _events: u32, _flags: u32, reply: ReplyPoll) {
+        reply.error(ENOSYS);
+    }
+
+    /// OS X only: Rename the volume. Set fuse_init_out.flags during init to
+    /// FUSE_VOL_RENAME to enable
+    #[cfg(target_os = "macos")]
+    fn setvolname (&self, _req: &Request, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// OS X only (undocumented)
+    #[cfg(target_os = "macos")]
+    fn exchange (&self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, _options: u64, reply: ReplyEmpty) {
+        reply.error(ENOSYS);
+    }
+
+    /// OS X only: Query extended times (bkuptime and crtime). Set fuse_init_out.flags
+    /// during init to FUSE_XTIMES to enable
+    #[cfg(target_os = "macos")]
+    fn getxtimes (&self, _req: &Request, _ino: u64, reply: ReplyXTimes) {
+        reply.error(ENOSYS);
+    }
+}
+
+/// Implements the methods of `Filesystem` by calling the corresponding methods
+/// of the concurrent filesystem that self dereferences to
+macro_rules! forward_to_concurrent_filesystem {
+    () => {
+        fn init (&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+            ConcurrentFilesystem::init(&**self, req, config)
+        }
+        fn destroy (&mut self, req: &Request) {
+            ConcurrentFilesystem::destroy(&**self, req)
+        }
+        fn lookup (&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            ConcurrentFilesystem::lookup(&**self, req, parent, name, reply)
+        }
+        fn forget (&mut self, req: &Request, ino: u64, nlookup: u64) {
+            ConcurrentFilesystem::forget(&**self, req, ino, nlookup)
+        }
+        fn batch_forget (&mut self, req: &Request, nodes: &[(u64, u64)]) {
+            ConcurrentFilesystem::batch_forget(&**self, req, nodes)
+        }
+        fn getattr (&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+            ConcurrentFilesystem::getattr(&**self, req, ino, reply)
+        }
+        fn setattr (&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<Timespec>, fh: Option<u64>, kill_suidgid: bool, crtime: Option<Timespec>, chgtime: Option<Timespec>, bkuptime: Option<Timespec>, flags: Option<u32>, reply: ReplyAttr) {
+            ConcurrentFilesystem::setattr(&**self, req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, kill_suidgid, crtime, chgtime, bkuptime, flags, reply)
+        }
+        fn readlink (&mut self, req: &Request, ino: u64, reply: ReplyData) {
+            ConcurrentFilesystem::readlink(&**self, req, ino, reply)
+        }
+        fn mknod (&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, rdev: u32, reply: ReplyEntry) {
+            ConcurrentFilesystem::mknod(&**self, req, parent, name, mode, rdev, reply)
+        }
+        fn mkdir (&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+            ConcurrentFilesystem::mkdir(&**self, req, parent, name, mode, reply)
+        }
+        fn unlink (&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+            ConcurrentFilesystem::unlink(&**self, req, parent, name, reply)
+        }
+        fn rmdir (&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+            ConcurrentFilesystem::rmdir(&**self, req, parent, name, reply)
+        }
+        fn symlink (&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+            ConcurrentFilesystem::symlink(&**self, req, parent, name, link, reply)
+        }
+        fn rename (&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: RenameFlags, reply: ReplyEmpty) {
+            ConcurrentFilesystem::rename(&**self, req, parent, name, newparent, newname, flags, reply)
+        }
+        fn link (&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+            ConcurrentFilesystem::link(&**self, req, ino, newparent, newname, reply)
+        }
+        fn open (&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+            ConcurrentFilesystem::open(&**self, req, ino, flags, reply)
+        }
+        fn read (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+            ConcurrentFilesystem::read(&**self, req, ino, fh, offset, size, reply)
+        }
+        fn write (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &[u8], write_flags: WriteFlags, lock_owner: Option<u64>, reply: ReplyWrite) {
+            ConcurrentFilesystem::write(&**self, req, ino, fh, offset, data, write_flags, lock_owner, reply)
+        }
+        fn write_buf (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, data: &WriteBuf, write_flags: WriteFlags, lock_owner: Option<u64>, reply: ReplyWrite) {
+            ConcurrentFilesystem::write_buf(&**self, req, ino, fh, offset, data, write_flags, lock_owner, reply)
+        }
+        fn flush (&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+            ConcurrentFilesystem::flush(&**self, req, ino, fh, lock_owner, reply)
+        }
+        fn release (&mut self, req: &Request, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool, reply: ReplyEmpty) {
+            ConcurrentFilesystem::release(&**self, req, ino, fh, flags, lock_owner, flush, reply)
+        }
+        fn fsync (&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+            ConcurrentFilesystem::fsync(&**self, req, ino, fh, datasync, reply)
+        }
+        fn opendir (&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+            ConcurrentFilesystem::opendir(&**self, req, ino, flags, reply)
+        }
+        fn readdir (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectory) {
+            ConcurrentFilesystem::readdir(&**self, req, ino, fh, offset, reply)
+        }
+        fn readdirplus (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, reply: ReplyDirectoryPlus) {
+            ConcurrentFilesystem::readdirplus(&**self, req, ino, fh, offset, reply)
+        }
+        fn releasedir (&mut self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+            ConcurrentFilesystem::releasedir(&**self, req, ino, fh, flags, reply)
+        }
+        fn fsyncdir (&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+            ConcurrentFilesystem::fsyncdir(&**self, req, ino, fh, datasync, reply)
+        }
+        fn statfs (&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+            ConcurrentFilesystem::statfs(&**self, req, ino, reply)
+        }
+        fn setxattr (&mut self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, position: u32, reply: ReplyEmpty) {
+            ConcurrentFilesystem::setxattr(&**self, req, ino, name, value, flags, position, reply)
+        }
+        fn getxattr (&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+            ConcurrentFilesystem::getxattr(&**self, req, ino, name, size, reply)
+        }
+        fn listxattr (&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+            ConcurrentFilesystem::listxattr(&**self, req, ino, size, reply)
+        }
+        fn removexattr (&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+            ConcurrentFilesystem::removexattr(&**self, req, ino, name, reply)
+        }
+        fn access (&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+            ConcurrentFilesystem::access(&**self, req, ino, mask, reply)
+        }
+        fn create (&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+            ConcurrentFilesystem::create(&**self, req, parent, name, mode, flags, reply)
+        }
+        fn getlk (&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, reply: ReplyLock) {
+            ConcurrentFilesystem::getlk(&**self, req, ino, fh, lock_owner, start, end, typ, pid, reply)
+        }
+        fn setlk (&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: u32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+            ConcurrentFilesystem::setlk(&**self, req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+        }
+        fn bmap (&mut self, req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+            ConcurrentFilesystem::bmap(&**self, req, ino, blocksize, idx, reply)
+        }
+        fn fallocate (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, length: u64, mode: FallocateFlags, reply: ReplyEmpty) {
+            ConcurrentFilesystem::fallocate(&**self, req, ino, fh, offset, length, mode, reply)
+        }
+        fn lseek (&mut self, req: &Request, ino: u64, fh: u64, offset: u64, whence: c_int, reply: ReplyLseek) {
+            ConcurrentFilesystem::lseek(&**self, req, ino, fh, offset, whence, reply)
+        }
+        fn copy_file_range (&mut self, req: &Request, ino_in: u64, fh_in: u64, offset_in: u64, ino_out: u64, fh_out: u64, offset_out: u64, len: u64, flags: u64, reply: ReplyWrite) {
+            ConcurrentFilesystem::copy_file_range(&**self, req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply)
+        }
+        fn ioctl (&mut self, req: &Request, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, in_data: &[u8], out_size: u32, reply: ReplyIoctl) {
+            ConcurrentFilesystem::ioctl(&**self, req, ino, fh, flags, cmd, arg, in_data, out_size, reply)
+        }
+        fn poll (&mut self, req: &Request, ino: u64, fh: u64, ph: PollHandle, events: u32, flags: u32, reply: ReplyPoll) {
+            ConcurrentFilesystem::poll(&**self, req, ino, fh, ph, events, flags, reply)
+        }
+        #[cfg(target_os = "macos")]
+        fn setvolname (&mut self, req: &Request, name: &OsStr, reply: ReplyEmpty) {
+            ConcurrentFilesystem::setvolname(&**self, req, name, reply)
+        }
+        #[cfg(target_os = "macos")]
+        fn exchange (&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, options: u64, reply: ReplyEmpty) {
+            ConcurrentFilesystem::exchange(&**self, req, parent, name, newparent, newname, options, reply)
+        }
+        #[cfg(target_os = "macos")]
+        fn getxtimes (&mut self, req: &Request, ino: u64, reply: ReplyXTimes) {
+            ConcurrentFilesystem::getxtimes(&**self, req, ino, reply)
+        }
+    }
+}
+
+impl<T: ConcurrentFilesystem> Filesystem for &T {
+    forward_to_concurrent_filesystem!();
+}
+
+impl<T: ConcurrentFilesystem> Filesystem for Arc<T> {
+    forward_to_concurrent_filesystem!();
+}
+
 /// Mount the given filesystem to the given mountpoint. This function will
-/// not return until the filesystem is unmounted.
-pub fn mount<FS: Filesystem, P: AsRef<Path>> (filesystem: FS, mountpoint: &P, options: &[&OsStr]) -> io::Result<()>{
+/// not return until the filesystem is unmounted and tells why the session ended.
+pub fn mount<FS: Filesystem, P: AsRef<Path>> (filesystem: FS, mountpoint: &P, options: &MountOptions) -> io::Result<ExitReason> {
     Session::new(filesystem, mountpoint.as_ref(), options).and_then(|mut se| se.run())
 }
 
+/// Mount the given asynchronous filesystem to the given mountpoint. Requests are
+/// received on the calling thread and the futures returned by the filesystem are
+/// run on the given executor. This function will not return until the filesystem
+/// is unmounted.
+pub fn mount_async<FS: AsyncFilesystem, E: Executor, P: AsRef<Path>> (filesystem: FS, executor: E, mountpoint: &P, options: &MountOptions) -> io::Result<ExitReason> {
+    mount(AsyncDriver::new(filesystem, executor), mountpoint, options)
+}
+
 /// Mount the given filesystem to the given mountpoint. This function spawns
 /// a background thread to handle filesystem operations while being mounted
 /// and therefore returns immediately. The returned handle should be stored
 /// to reference the mounted filesystem. If it's dropped, the filesystem will
 /// be unmounted.
-pub unsafe fn spawn_mount<'a, FS: Filesystem+Send+'a, P: AsRef<Path>> (filesystem: FS, mountpoint: &P, options: &[&OsStr]) -> io::Result<BackgroundSession<'a>> {
+pub fn spawn_mount<FS: Filesystem+Send+'static, P: AsRef<Path>> (filesystem: FS, mountpoint: &P, options: &MountOptions) -> io::Result<BackgroundSession<FS>> {
     Session::new(filesystem, mountpoint.as_ref(), options).and_then(|se| se.spawn())
 }
+
+/// Unmount the filesystem mounted at the given mountpoint using the given flags.
+/// A lazy unmount detaches a busy filesystem immediately, a forced unmount (which
+/// requires privileges) works even if the filesystem doesn't respond.
+pub fn unmount_with<P: AsRef<Path>> (mountpoint: &P, flags: UnmountFlags) -> io::Result<()> {
+    channel::unmount_with(mountpoint.as_ref(), flags)
+}
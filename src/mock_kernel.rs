@@ -0,0 +1,817 @@
+//!
+//! In-process mock of the kernel driver for testing filesystem implementations
+//! without mounting them. The mock kernel talks to a session over a socket pair
+//! instead of /dev/fuse. It encodes requests like the kernel driver does, lets
+//! the session dispatch them to the filesystem and decodes the replies into
+//! typed results.
+//!
+
+use std::{io, mem};
+use std::ffi::OsStr;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use libc::{self, c_int, S_IFMT, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFLNK};
+use time::Timespec;
+use argument::ArgumentIterator;
+use async_fs::{Entry, Opened, Statfs, Xattr, DirEntry, DirEntryPlus, Ioctl};
+use channel::Channel;
+use fuse::*;
+use fuse::consts::*;
+use fuse::fuse_opcode::*;
+use reply::as_bytes;
+use session::{Session, BUFFER_SIZE};
+use {FallocateFlags, FileType, FileAttr, Filesystem, IoctlFlags, RenameFlags, TimeOrNow, WriteFlags};
+
+/// Max readahead the mock kernel offers on init
+const MAX_READAHEAD: u32 = 128 * 1024;
+
+/// Returns the bytes of the given argument struct
+fn arg_bytes<T> (arg: &T) -> Vec<u8> {
+    as_bytes(arg, |bytes| bytes.concat())
+}
+
+/// Returns the bytes of the given name with a terminating zero
+fn name_bytes (name: &OsStr) -> Vec<u8> {
+    let mut bytes = name.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+/// Decode a struct from the beginning of the given reply data
+fn decode<T> (data: &[u8]) -> T {
+    ArgumentIterator::new(data).fetch().expect("mock kernel received a short reply")
+}
+
+// Some platforms like Linux x86_64 have mode_t = u32, and lint warns of a trivial_numeric_casts.
+// But others like MacOS x86_64 have mode_t = u16, requiring a typecast.  So, just silence lint.
+#[allow(trivial_numeric_casts)]
+/// Returns the file kind of the given mode
+fn kind_from_mode (mode: u32) -> FileType {
+    match mode & S_IFMT as u32 {
+        kind if kind == S_IFIFO as u32 => FileType::NamedPipe,
+        kind if kind == S_IFCHR as u32 => FileType::CharDevice,
+        kind if kind == S_IFBLK as u32 => FileType::BlockDevice,
+        kind if kind == S_IFDIR as u32 => FileType::Directory,
+        kind if kind == S_IFLNK as u32 => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
+}
+
+/// Returns a FileAttr from fuse_attr
+#[cfg(target_os = "macos")]
+fn attr_from_fuse_attr (attr: &fuse_attr) -> FileAttr {
+    FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: Timespec::new(attr.atime, attr.atimensec),
+        mtime: Timespec::new(attr.mtime, attr.mtimensec),
+        ctime: Timespec::new(attr.ctime, attr.ctimensec),
+        crtime: Timespec::new(attr.crtime, attr.crtimensec),
+        kind: kind_from_mode(attr.mode),
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        flags: attr.flags,
+        blksize: attr.blksize,
+    }
+}
+
+/// Returns a FileAttr from fuse_attr
+#[cfg(not(target_os = "macos"))]
+fn attr_from_fuse_attr (attr: &fuse_attr) -> FileAttr {
+    FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: Timespec::new(attr.atime, attr.atimensec),
+        mtime: Timespec::new(attr.mtime, attr.mtimensec),
+        ctime: Timespec::new(attr.ctime, attr.ctimensec),
+        crtime: Timespec::new(0, 0),
+        kind: kind_from_mode(attr.mode),
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        flags: 0,
+        blksize: attr.blksize,
+    }
+}
+
+/// Returns an Entry from fuse_entry_out
+fn entry_from_entry_out (entry: &fuse_entry_out) -> Entry {
+    Entry {
+        ttl: Timespec::new(entry.entry_valid, entry.entry_valid_nsec),
+        attr: attr_from_fuse_attr(&entry.attr),
+        generation: entry.generation,
+    }
+}
+
+/// Returns the size of a directory entry with the given name length, 64bit aligned
+fn dirent_size (len: usize) -> usize {
+    (len + size_of::<u64>() - 1) & !(size_of::<u64>() - 1)
+}
+
+/// Mock of the kernel driver that runs a filesystem in-process. Every operation
+/// sends a request to the filesystem, dispatches it and waits for the reply, so
+/// filesystem methods may reply asynchronously, but must reply eventually. The
+/// filesystem is initialized when the mock kernel is created.
+#[derive(Debug)]
+pub struct MockKernel<FS: Filesystem> {
+    /// Session that runs the filesystem
+    session: Session<FS>,
+    /// Kernel end of the channel to the session
+    ch: Channel,
+    /// Unique id of the next request
+    next_unique: u64,
+    /// Capabilities requested by the filesystem on init
+    capabilities: u32,
+    /// Buffer for receiving requests and replies, reused for every request
+    buffer: Vec<u8>,
+}
+
+impl<FS: Filesystem> MockKernel<FS> {
+    /// Create a mock kernel for the given filesystem and initialize the filesystem.
+    /// The mock kernel offers all capabilities to the filesystem.
+    pub fn new (filesystem: FS) -> io::Result<MockKernel<FS>> {
+        MockKernel::with_capabilities(filesystem, !0)
+    }
+
+    /// Create a mock kernel for the given filesystem and initialize the filesystem.
+    /// The mock kernel offers the given capabilities (FUSE_*) to the filesystem.
+    pub fn with_capabilities (filesystem: FS, capabilities: u32) -> io::Result<MockKernel<FS>> {
+        let (ch, session_ch) = Channel::pair()?;
+        let mut kernel = MockKernel {
+            session: Session::with_channel(filesystem, session_ch),
+            ch: ch,
+            next_unique: 1,
+            capabilities: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+        };
+        let arg = fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: MAX_READAHEAD,
+            flags: capabilities,
+            flags2: 0,
+            unused: [0; 11],
+        };
+        match kernel.call(FUSE_INIT, 0, &[&arg_bytes(&arg)]) {
+            Ok(data) => {
+                let init: fuse_init_out = decode(&data);
+                kernel.capabilities = init.flags;
+                Ok(kernel)
+            },
+            Err(err) => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    /// Returns the filesystem
+    pub fn filesystem (&self) -> &FS {
+        &self.session.filesystem
+    }
+
+    /// Returns the filesystem for modification
+    pub fn filesystem_mut (&mut self) -> &mut FS {
+        &mut self.session.filesystem
+    }
+
+    /// Returns the capabilities (FUSE_*) requested by the filesystem on init
+    pub fn capabilities (&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Send a request with the given opcode, node id and arguments to the session
+    /// and return its unique id
+    fn send (&mut self, opcode: fuse_opcode, nodeid: u64, args: &[&[u8]]) -> u64 {
+        let unique = self.next_unique;
+        self.next_unique += 1;
+        let len = args.iter().fold(size_of::<fuse_in_header>(), |l, a| l + a.len());
+        let header = fuse_in_header {
+            len: len as u32,
+            opcode: opcode as u32,
+            unique: unique,
+            nodeid: nodeid,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            pid: unsafe { libc::getpid() } as u32,
+            padding: 0,
+        };
+        let mut request = as_bytes(&header, |headerbytes| headerbytes.concat());
+        for arg in args {
+            request.extend_from_slice(arg);
+        }
+        self.dispatch_raw(&request);
+        unique
+    }
+
+    /// Send the given bytes as a request to the session and let the session dispatch
+    /// it. Replies are not waited for. This is useful for testing how malformed
+    /// requests are handled, e.g. by fuzzing.
+    pub fn dispatch_raw (&mut self, request: &[u8]) {
+        self.ch.sender().send(&[request]).expect("mock kernel failed to send request");
+        let _ = self.session.process(&mut self.buffer).expect("mock kernel failed to dispatch request");
+    }
+
+    /// Wait for the reply to the request with the given unique id. Returns the reply
+    /// data or the error code.
+    fn receive (&mut self, unique: u64) -> Result<Vec<u8>, c_int> {
+        loop {
+            self.ch.receive(&mut self.buffer).expect("mock kernel failed to receive reply");
+            let header: fuse_out_header = decode(&self.buffer);
+            assert_eq!(header.len as usize, self.buffer.len(), "invalid length of reply {}", header.unique);
+            // Skip notifications and replies to other requests (e.g. EAGAIN for interrupts)
+            if header.unique != unique {
+                debug!("Mock kernel ignoring reply {}, error {}", header.unique, header.error);
+                continue;
+            }
+            return match header.error {
+                0 => Ok(self.buffer[size_of::<fuse_out_header>()..].to_vec()),
+                error => Err(-error),
+            };
+        }
+    }
+
+    /// Send a request, dispatch it and wait for its reply
+    fn call (&mut self, opcode: fuse_opcode, nodeid: u64, args: &[&[u8]]) -> Result<Vec<u8>, c_int> {
+        let unique = self.send(opcode, nodeid, args);
+        self.receive(unique)
+    }
+
+    /// Look up a directory entry by name and get its attributes
+    pub fn lookup<T: AsRef<OsStr>> (&mut self, parent: u64, name: T) -> Result<FileAttr, c_int> {
+        let data = self.call(FUSE_LOOKUP, parent, &[&name_bytes(name.as_ref())])?;
+        Ok(attr_from_fuse_attr(&decode::<fuse_entry_out>(&data).attr))
+    }
+
+    /// Forget about an inode (there's no reply)
+    pub fn forget (&mut self, ino: u64, nlookup: u64) {
+        let arg = fuse_forget_in { nlookup: nlookup };
+        self.send(FUSE_FORGET, ino, &[&arg_bytes(&arg)]);
+    }
+
+    /// Forget about multiple inodes, given as pairs of inode number and nlookup
+    /// (there's no reply)
+    pub fn batch_forget (&mut self, nodes: &[(u64, u64)]) {
+        let arg = fuse_batch_forget_in { count: nodes.len() as u32, dummy: 0 };
+        let mut args = vec![arg_bytes(&arg)];
+        args.extend(nodes.iter().map(|&(ino, nlookup)| arg_bytes(&fuse_forget_one { nodeid: ino, nlookup: nlookup })));
+        let args: Vec<&[u8]> = args.iter().map(|arg| &arg[..]).collect();
+        self.send(FUSE_BATCH_FORGET, 0, &args);
+    }
+
+    /// Get file attributes
+    pub fn getattr (&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        let arg = fuse_getattr_in { getattr_flags: 0, dummy: 0, fh: 0 };
+        let data = self.call(FUSE_GETATTR, ino, &[&arg_bytes(&arg)])?;
+        Ok(attr_from_fuse_attr(&decode::<fuse_attr_out>(&data).attr))
+    }
+
+    /// Set file attributes. Only attributes that are given are changed.
+    pub fn setattr (&mut self, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<Timespec>, fh: Option<u64>) -> Result<FileAttr, c_int> {
+        let mut arg: fuse_setattr_in = unsafe { mem::zeroed() };
+        if let Some(mode) = mode { arg.valid |= FATTR_MODE; arg.mode = mode; }
+        if let Some(uid) = uid { arg.valid |= FATTR_UID; arg.uid = uid; }
+        if let Some(gid) = gid { arg.valid |= FATTR_GID; arg.gid = gid; }
+        if let Some(size) = size { arg.valid |= FATTR_SIZE; arg.size = size; }
+        match atime {
+            Some(TimeOrNow::SpecificTime(atime)) => { arg.valid |= FATTR_ATIME; arg.atime = atime.sec; arg.atimensec = atime.nsec; },
+            Some(TimeOrNow::Now) => arg.valid |= FATTR_ATIME | FATTR_ATIME_NOW,
+            None => (),
+        }
+        match mtime {
+            Some(TimeOrNow::SpecificTime(mtime)) => { arg.valid |= FATTR_MTIME; arg.mtime = mtime.sec; arg.mtimensec = mtime.nsec; },
+            Some(TimeOrNow::Now) => arg.valid |= FATTR_MTIME | FATTR_MTIME_NOW,
+            None => (),
+        }
+        if let Some(ctime) = ctime { arg.valid |= FATTR_CTIME; arg.ctime = ctime.sec; arg.ctimensec = ctime.nsec; }
+        if let Some(fh) = fh { arg.valid |= FATTR_FH; arg.fh = fh; }
+        let data = self.call(FUSE_SETATTR, ino, &[&arg_bytes(&arg)])?;
+        Ok(attr_from_fuse_attr(&decode::<fuse_attr_out>(&data).attr))
+    }
+
+    /// Read the target of a symbolic link
+    pub fn readlink (&mut self, ino: u64) -> Result<PathBuf, c_int> {
+        let data = self.call(FUSE_READLINK, ino, &[])?;
+        Ok(PathBuf::from(OsStr::from_bytes(&data)))
+    }
+
+    /// Create a file node
+    pub fn mknod<T: AsRef<OsStr>> (&mut self, parent: u64, name: T, mode: u32, rdev: u32) -> Result<FileAttr, c_int> {
+        let arg = fuse_mknod_in { mode: mode, rdev: rdev, umask: 0, padding: 0 };
+        let data = self.call(FUSE_MKNOD, parent, &[&arg_bytes(&arg), &name_bytes(name.as_ref())])?;
+        Ok(attr_from_fuse_attr(&decode::<fuse_entry_out>(&data).attr))
+    }
+
+    /// Create a directory
+    pub fn mkdir<T: AsRef<OsStr>> (&mut self, parent: u64, name: T, mode: u32) -> Result<FileAttr, c_int> {
+        let arg = fuse_mkdir_in { mode: mode, umask: 0 };
+        let data = self.call(FUSE_MKDIR, parent, &[&arg_bytes(&arg), &name_bytes(name.as_ref())])?;
+        Ok(attr_from_fuse_attr(&decode::<fuse_entry_out>(&data).attr))
+    }
+
+    /// Remove a file
+    pub fn unlink<T: AsRef<OsStr>> (&mut self, parent: u64, name: T) -> Result<(), c_int> {
+        self.call(FUSE_UNLINK, parent, &[&name_bytes(name.as_ref())]).map(|_| ())
+    }
+
+    /// Remove a directory
+    pub fn rmdir<T: AsRef<OsStr>> (&mut self, parent: u64, name: T) -> Result<(), c_int> {
+        self.call(FUSE_RMDIR, parent, &[&name_bytes(name.as_ref())]).map(|_| ())
+    }
+
+    /// Create a symbolic link
+    pub fn symlink<T: AsRef<OsStr>> (&mut self, parent: u64, name: T, link: &Path) -> Result<FileAttr, c_int> {
+        let data = self.call(FUSE_SYMLINK, parent, &[&name_bytes(name.as_ref()), &name_bytes(link.as_os_str())])?;
+        Ok(attr_from_fuse_attr(&decode::<fuse_entry_out>(&data).attr))
+    }
+
+    /// Rename a file. Like the kernel, RENAME2 is only used if flags are given.
+    pub fn rename<T: AsRef<OsStr>, U: AsRef<OsStr>> (&mut self, parent: u64, name: T, newparent: u64, newname: U, flags: RenameFlags) -> Result<(), c_int> {
+        let names = [name_bytes(name.as_ref()), name_bytes(newname.as_ref())];
+        if flags.is_empty() {
+            let arg = fuse_rename_in { newdir: newparent };
+            self.call(FUSE_RENAME, parent, &[&arg_bytes(&arg), &names[0], &names[1]]).map(|_| ())
+        } else {
+            let arg = fuse_rename2_in { newdir: newparent, flags: flags.bits(), padding: 0 };
+            self.call(FUSE_RENAME2, parent, &[&arg_bytes(&arg), &names[0], &names[1]]).map(|_| ())
+        }
+    }
+
+    /// Create a hard link
+    pub fn link<T: AsRef<OsStr>> (&mut self, ino: u64, newparent: u64, newname: T) -> Result<FileAttr, c_int> {
+        let arg = fuse_link_in { oldnodeid: ino };
+        let data = self.call(FUSE_LINK, newparent, &[&arg_bytes(&arg), &name_bytes(newname.as_ref())])?;
+        Ok(attr_from_fuse_attr(&decode::<fuse_entry_out>(&data).attr))
+    }
+
+    /// Open a file
+    pub fn open (&mut self, ino: u64, flags: u32) -> Result<Opened, c_int> {
+        let arg = fuse_open_in { flags: flags, open_flags: 0 };
+        let data = self.call(FUSE_OPEN, ino, &[&arg_bytes(&arg)])?;
+        let open: fuse_open_out = decode(&data);
+        Ok(Opened { fh: open.fh, flags: open.open_flags })
+    }
+
+    /// Read data
+    pub fn read (&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        let arg = fuse_read_in { fh: fh, offset: offset, size: size, read_flags: 0, lock_owner: 0, flags: 0, padding: 0 };
+        self.call(FUSE_READ, ino, &[&arg_bytes(&arg)])
+    }
+
+    /// Write data. The LOCKOWNER flag is set if a lock owner is given. Returns the
+    /// number of bytes written.
+    pub fn write (&mut self, ino: u64, fh: u64, offset: u64, data: &[u8], flags: WriteFlags, lock_owner: Option<u64>) -> Result<u32, c_int> {
+        let flags = match lock_owner { Some(_) => flags | WriteFlags::LOCKOWNER, None => flags };
+        let arg = fuse_write_in { fh: fh, offset: offset, size: data.len() as u32, write_flags: flags.bits(), lock_owner: lock_owner.unwrap_or(0), flags: 0, padding: 0 };
+        let data = self.call(FUSE_WRITE, ino, &[&arg_bytes(&arg), data])?;
+        Ok(decode::<fuse_write_out>(&data).size)
+    }
+
+    /// Flush an open file
+    pub fn flush (&mut self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), c_int> {
+        let arg = fuse_flush_in { fh: fh, unused: 0, padding: 0, lock_owner: lock_owner };
+        self.call(FUSE_FLUSH, ino, &[&arg_bytes(&arg)]).map(|_| ())
+    }
+
+    /// Release an open file
+    pub fn release (&mut self, ino: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) -> Result<(), c_int> {
+        let release_flags = match flush { true => FUSE_RELEASE_FLUSH, false => 0 };
+        let arg = fuse_release_in { fh: fh, flags: flags, release_flags: release_flags, lock_owner: lock_owner };
+        self.call(FUSE_RELEASE, ino, &[&arg_bytes(&arg)]).map(|_| ())
+    }
+
+    /// Synchronize file contents
+    pub fn fsync (&mut self, ino: u64, fh: u64, datasync: bool) -> Result<(), c_int> {
+        let arg = fuse_fsync_in { fh: fh, fsync_flags: datasync as u32, padding: 0 };
+        self.call(FUSE_FSYNC, ino, &[&arg_bytes(&arg)]).map(|_| ())
+    }
+
+    /// Open a directory
+    pub fn opendir (&mut self, ino: u64, flags: u32) -> Result<Opened, c_int> {
+        let arg = fuse_open_in { flags: flags, open_flags: 0 };
+        let data = self.call(FUSE_OPENDIR, ino, &[&arg_bytes(&arg)])?;
+        let open: fuse_open_out = decode(&data);
+        Ok(Opened { fh: open.fh, flags: open.open_flags })
+    }
+
+    /// Read directory entries that fit into the given size
+    pub fn readdir (&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<DirEntry>, c_int> {
+        let arg = fuse_read_in { fh: fh, offset: offset, size: size, read_flags: 0, lock_owner: 0, flags: 0, padding: 0 };
+        let data = self.call(FUSE_READDIR, ino, &[&arg_bytes(&arg)])?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let dirent: fuse_dirent = decode(&data[pos..]);
+            let name = &data[pos + size_of::<fuse_dirent>()..][..dirent.namelen as usize];
+            entries.push(DirEntry {
+                ino: dirent.ino,
+                offset: dirent.off,
+                kind: kind_from_mode(dirent.typ << 12),
+                name: OsStr::from_bytes(name).to_os_string(),
+            });
+            pos += dirent_size(size_of::<fuse_dirent>() + name.len());
+        }
+        Ok(entries)
+    }
+
+    /// Read directory entries with attributes that fit into the given size
+    pub fn readdirplus (&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<DirEntryPlus>, c_int> {
+        let arg = fuse_read_in { fh: fh, offset: offset, size: size, read_flags: 0, lock_owner: 0, flags: 0, padding: 0 };
+        let data = self.call(FUSE_READDIRPLUS, ino, &[&arg_bytes(&arg)])?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let direntplus: fuse_direntplus = decode(&data[pos..]);
+            let name = &data[pos + size_of::<fuse_direntplus>()..][..direntplus.dirent.namelen as usize];
+            entries.push(DirEntryPlus {
+                offset: direntplus.dirent.off,
+                name: OsStr::from_bytes(name).to_os_string(),
+                entry: entry_from_entry_out(&direntplus.entry_out),
+            });
+            pos += dirent_size(size_of::<fuse_direntplus>() + name.len());
+        }
+        Ok(entries)
+    }
+
+    /// Release an open directory
+    pub fn releasedir (&mut self, ino: u64, fh: u64, flags: u32) -> Result<(), c_int> {
+        let arg = fuse_release_in { fh: fh, flags: flags, release_flags: 0, lock_owner: 0 };
+        self.call(FUSE_RELEASEDIR, ino, &[&arg_bytes(&arg)]).map(|_| ())
+    }
+
+    /// Synchronize directory contents
+    pub fn fsyncdir (&mut self, ino: u64, fh: u64, datasync: bool) -> Result<(), c_int> {
+        let arg = fuse_fsync_in { fh: fh, fsync_flags: datasync as u32, padding: 0 };
+        self.call(FUSE_FSYNCDIR, ino, &[&arg_bytes(&arg)]).map(|_| ())
+    }
+
+    /// Get filesystem statistics
+    pub fn statfs (&mut self, ino: u64) -> Result<Statfs, c_int> {
+        let data = self.call(FUSE_STATFS, ino, &[])?;
+        let st = decode::<fuse_statfs_out>(&data).st;
+        Ok(Statfs { blocks: st.blocks, bfree: st.bfree, bavail: st.bavail, files: st.files, ffree: st.ffree, bsize: st.bsize, namelen: st.namelen, frsize: st.frsize })
+    }
+
+    /// Get an extended attribute. If size is 0, the size of the value is returned.
+    pub fn getxattr<T: AsRef<OsStr>> (&mut self, ino: u64, name: T, size: u32) -> Result<Xattr, c_int> {
+        let mut arg: fuse_getxattr_in = unsafe { mem::zeroed() };
+        arg.size = size;
+        let data = self.call(FUSE_GETXATTR, ino, &[&arg_bytes(&arg), &name_bytes(name.as_ref())])?;
+        Ok(match size {
+            0 => Xattr::Size(decode::<fuse_getxattr_out>(&data).size),
+            _ => Xattr::Data(data),
+        })
+    }
+
+    /// List extended attribute names. If size is 0, the size of the list is returned.
+    pub fn listxattr (&mut self, ino: u64, size: u32) -> Result<Xattr, c_int> {
+        let mut arg: fuse_getxattr_in = unsafe { mem::zeroed() };
+        arg.size = size;
+        let data = self.call(FUSE_LISTXATTR, ino, &[&arg_bytes(&arg)])?;
+        Ok(match size {
+            0 => Xattr::Size(decode::<fuse_getxattr_out>(&data).size),
+            _ => Xattr::Data(data),
+        })
+    }
+
+    /// Check file access permissions
+    pub fn access (&mut self, ino: u64, mask: u32) -> Result<(), c_int> {
+        let arg = fuse_access_in { mask: mask, padding: 0 };
+        self.call(FUSE_ACCESS, ino, &[&arg_bytes(&arg)]).map(|_| ())
+    }
+
+    /// Create and open a file
+    pub fn create<T: AsRef<OsStr>> (&mut self, parent: u64, name: T, mode: u32, flags: u32) -> Result<(FileAttr, Opened), c_int> {
+        let arg = fuse_create_in { flags: flags, mode: mode, umask: 0, open_flags: 0 };
+        let data = self.call(FUSE_CREATE, parent, &[&arg_bytes(&arg), &name_bytes(name.as_ref())])?;
+        let entry: fuse_entry_out = decode(&data);
+        let open: fuse_open_out = decode(&data[size_of::<fuse_entry_out>()..]);
+        Ok((attr_from_fuse_attr(&entry.attr), Opened { fh: open.fh, flags: open.open_flags }))
+    }
+
+    /// Allocate or deallocate space of an open file
+    pub fn fallocate (&mut self, ino: u64, fh: u64, offset: u64, length: u64, mode: FallocateFlags) -> Result<(), c_int> {
+        let arg = fuse_fallocate_in { fh: fh, offset: offset, length: length, mode: mode.bits(), padding: 0 };
+        self.call(FUSE_FALLOCATE, ino, &[&arg_bytes(&arg)]).map(|_| ())
+    }
+
+    /// Find the next data or hole of an open file
+    pub fn lseek (&mut self, ino: u64, fh: u64, offset: u64, whence: c_int) -> Result<u64, c_int> {
+        let arg = fuse_lseek_in { fh: fh, offset: offset, whence: whence as u32, padding: 0 };
+        let data = self.call(FUSE_LSEEK, ino, &[&arg_bytes(&arg)])?;
+        Ok(decode::<fuse_lseek_out>(&data).offset)
+    }
+
+    /// Copy a range of data from one open file to another
+    pub fn copy_file_range (&mut self, ino_in: u64, fh_in: u64, offset_in: u64, ino_out: u64, fh_out: u64, offset_out: u64, len: u64, flags: u64) -> Result<u32, c_int> {
+        let arg = fuse_copy_file_range_in { fh_in: fh_in, off_in: offset_in, nodeid_out: ino_out, fh_out: fh_out, off_out: offset_out, len: len, flags: flags };
+        let data = self.call(FUSE_COPY_FILE_RANGE, ino_in, &[&arg_bytes(&arg)])?;
+        Ok(decode::<fuse_write_out>(&data).size)
+    }
+
+    /// Control device. Replies asking for a retry are returned rather than retried.
+    pub fn ioctl (&mut self, ino: u64, fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, in_data: &[u8], out_size: u32) -> Result<Ioctl, c_int> {
+        let arg = fuse_ioctl_in { fh: fh, flags: flags.bits(), cmd: cmd, arg: arg, in_size: in_data.len() as u32, out_size: out_size };
+        let data = self.call(FUSE_IOCTL, ino, &[&arg_bytes(&arg), in_data])?;
+        let out: fuse_ioctl_out = decode(&data);
+        let data = &data[size_of::<fuse_ioctl_out>()..];
+        if out.flags & FUSE_IOCTL_RETRY == 0 {
+            return Ok(Ioctl::Data(out.result, data.to_vec()));
+        }
+        let mut iovs = ArgumentIterator::new(data);
+        let mut fetch_iovs = |count| (0..count).map(|_| {
+            let iov: fuse_ioctl_iovec = iovs.fetch().expect("mock kernel received short ioctl reply");
+            (iov.base, iov.len)
+        }).collect();
+        let in_iovs = fetch_iovs(out.in_iovs);
+        let out_iovs = fetch_iovs(out.out_iovs);
+        Ok(Ioctl::Retry(in_iovs, out_iovs))
+    }
+
+    /// Poll for events of an open file. Returns the events that are ready.
+    pub fn poll (&mut self, ino: u64, fh: u64, kh: u64, events: u32, flags: u32) -> Result<u32, c_int> {
+        let arg = fuse_poll_in { fh: fh, kh: kh, flags: flags, events: events };
+        let data = self.call(FUSE_POLL, ino, &[&arg_bytes(&arg)])?;
+        Ok(decode::<fuse_poll_out>(&data).revents)
+    }
+
+    /// Destroy the filesystem like the kernel does on unmount
+    pub fn destroy (&mut self) -> Result<(), c_int> {
+        self.call(FUSE_DESTROY, 0, &[]).map(|_| ())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+    use std::ffi::OsStr;
+    use std::os::unix::io::AsRawFd;
+    use libc::{c_int, EINVAL, EIO, ENOENT, ENOSYS, ENOTTY, SEEK_DATA};
+    use time::Timespec;
+    use fuse::{fuse_write_in, fuse_forget_in, fuse_batch_forget_in, fuse_forget_one};
+    use fuse::consts::{FUSE_SPLICE_MOVE, FUSE_SPLICE_READ, FUSE_SPLICE_WRITE, FUSE_WRITEBACK_CACHE};
+    use fuse::fuse_opcode::*;
+    use async_fs::Ioctl;
+    use super::{MockKernel, arg_bytes};
+    use {FallocateFlags, FileType, FileAttr, Filesystem, IoctlFlags, KernelConfig, RenameFlags, Request, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyWrite, TimeOrNow, WriteBuf, WriteFlags};
+
+    const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+    /// Filesystem with a single file named "hello" that contains "Hello World!"
+    struct HelloFS;
+
+    fn attr (ino: u64, kind: FileType, size: u64) -> FileAttr {
+        let time = Timespec::new(1, 0);
+        FileAttr { ino: ino, size: size, blocks: 1, atime: time, mtime: time, ctime: time, crtime: Timespec::new(0, 0), kind: kind, perm: 0o644, nlink: 1, uid: 501, gid: 20, rdev: 0, flags: 0, blksize: 512 }
+    }
+
+    impl Filesystem for HelloFS {
+        fn lookup (&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            match (parent, name.to_str()) {
+                (1, Some("hello")) => reply.entry(&TTL, &attr(2, FileType::RegularFile, 12), 0),
+                _ => reply.error(ENOENT),
+            }
+        }
+
+        fn getattr (&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+            match ino {
+                1 => reply.attr(&TTL, &attr(1, FileType::Directory, 0)),
+                _ => reply.error(ENOENT),
+            }
+        }
+
+        fn read (&mut self, _req: &Request, _ino: u64, _fh: u64, offset: u64, _size: u32, reply: ReplyData) {
+            reply.data(&b"Hello World!"[offset as usize..]);
+        }
+
+        fn readdir (&mut self, _req: &Request, _ino: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
+            let entries = [(1, FileType::Directory, "."), (1, FileType::Directory, ".."), (2, FileType::RegularFile, "hello")];
+            for (i, &(ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+                reply.add(ino, i as u64 + 1, kind, name);
+            }
+            reply.ok();
+        }
+    }
+
+    #[test]
+    fn hello () {
+        let mut kernel = MockKernel::new(HelloFS).unwrap();
+        let attr = kernel.lookup(1, "hello").unwrap();
+        assert_eq!(attr.ino, 2);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(attr.size, 12);
+        assert_eq!(kernel.lookup(1, "nope").unwrap_err(), ENOENT);
+        assert_eq!(kernel.getattr(1).unwrap().kind, FileType::Directory);
+        let fh = kernel.open(2, 0).unwrap().fh;
+        assert_eq!(kernel.read(2, fh, 6, 4096).unwrap(), b"World!");
+        let entries = kernel.readdir(1, 0, 1, 4096).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.to_str().unwrap()).collect();
+        assert_eq!(names, ["..", "hello"]);
+        assert_eq!(entries[1].ino, 2);
+        assert_eq!(entries[1].offset, 3);
+        assert_eq!(entries[1].kind, FileType::RegularFile);
+        kernel.release(2, fh, 0, 0, false).unwrap();
+        kernel.destroy().unwrap();
+    }
+
+    #[test]
+    fn unimplemented () {
+        let mut kernel = MockKernel::new(HelloFS).unwrap();
+        assert_eq!(kernel.mkdir(1, "dir", 0o755).unwrap_err(), ENOSYS);
+        assert_eq!(kernel.write(2, 0, 0, b"foo", WriteFlags::default(), None).unwrap_err(), ENOSYS);
+        assert_eq!(kernel.fallocate(2, 0, 0, 4096, FallocateFlags::KEEP_SIZE).unwrap_err(), ENOSYS);
+        assert_eq!(kernel.lseek(2, 0, 0, SEEK_DATA).unwrap_err(), ENOSYS);
+        assert_eq!(kernel.copy_file_range(2, 0, 0, 2, 0, 4096, 12, 0).unwrap_err(), ENOSYS);
+        assert_eq!(kernel.poll(2, 0, 1, 0x1, 0).unwrap_err(), ENOSYS);
+    }
+
+    #[test]
+    fn malformed () {
+        let mut kernel = MockKernel::new(HelloFS).unwrap();
+        // Missing terminating zero of name
+        assert_eq!(kernel.call(FUSE_LOOKUP, 1, &[b"hello"]).unwrap_err(), EIO);
+        // Truncated argument struct
+        assert_eq!(kernel.call(FUSE_WRITE, 2, &[&[0; 8]]).unwrap_err(), EIO);
+        // Size of data doesn't match size in argument struct
+        let arg = fuse_write_in { fh: 0, offset: 0, size: 8, write_flags: 0, lock_owner: 0, flags: 0, padding: 0 };
+        assert_eq!(kernel.call(FUSE_WRITE, 2, &[&arg_bytes(&arg), b"foo"]).unwrap_err(), EINVAL);
+        // Operations without reply are ignored
+        kernel.send(FUSE_FORGET, 2, &[&arg_bytes(&fuse_forget_in { nlookup: 1 })[..4]]);
+        // Requests shorter than a header are ignored
+        kernel.dispatch_raw(&[0; 7]);
+        assert_eq!(kernel.lookup(1, "hello").unwrap().ino, 2);
+    }
+
+    /// Filesystem that remembers forgotten inodes
+    struct ForgetFS(Vec<(u64, u64)>);
+
+    impl Filesystem for ForgetFS {
+        fn forget (&mut self, _req: &Request, ino: u64, nlookup: u64) {
+            self.0.push((ino, nlookup));
+        }
+    }
+
+    /// Filesystem with an unrestricted ioctl that reverses the data at its argument
+    struct IoctlFS;
+
+    impl Filesystem for IoctlFS {
+        fn ioctl (&mut self, _req: &Request, _ino: u64, _fh: u64, flags: IoctlFlags, cmd: u32, arg: u64, in_data: &[u8], out_size: u32, reply: ReplyIoctl) {
+            match (cmd, in_data.is_empty()) {
+                (1, _) if !flags.contains(IoctlFlags::UNRESTRICTED) => reply.error(EINVAL),
+                (1, true) => reply.retry(&[(arg, 4)], &[(arg, 4)]),
+                (1, false) => {
+                    assert_eq!(out_size, 4);
+                    let data: Vec<u8> = in_data.iter().rev().cloned().collect();
+                    reply.ioctl(0, &data);
+                },
+                _ => reply.error(ENOTTY),
+            }
+        }
+    }
+
+    #[test]
+    fn ioctl () {
+        let mut kernel = MockKernel::new(IoctlFS).unwrap();
+        let flags = IoctlFlags::UNRESTRICTED;
+        match kernel.ioctl(2, 0, flags, 1, 0x1000, &[], 0).unwrap() {
+            Ioctl::Retry(in_iovs, out_iovs) => {
+                assert_eq!(in_iovs, [(0x1000, 4)]);
+                assert_eq!(out_iovs, [(0x1000, 4)]);
+            },
+            ioctl => panic!("unexpected ioctl reply {:?}", ioctl),
+        }
+        match kernel.ioctl(2, 0, flags, 1, 0x1000, b"abcd", 4).unwrap() {
+            Ioctl::Data(result, data) => {
+                assert_eq!(result, 0);
+                assert_eq!(data, b"dcba");
+            },
+            ioctl => panic!("unexpected ioctl reply {:?}", ioctl),
+        }
+        assert_eq!(kernel.ioctl(2, 0, IoctlFlags::default(), 1, 0x1000, &[], 0).unwrap_err(), EINVAL);
+        assert_eq!(kernel.ioctl(2, 0, flags, 2, 0, &[], 0).unwrap_err(), ENOTTY);
+    }
+
+    /// Filesystem that remembers flags of renames
+    struct RenameFS(Vec<RenameFlags>);
+
+    impl Filesystem for RenameFS {
+        fn rename (&mut self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, flags: RenameFlags, reply: ReplyEmpty) {
+            self.0.push(flags);
+            match flags.contains(RenameFlags::WHITEOUT) {
+                true => reply.error(EINVAL),
+                false => reply.ok(),
+            }
+        }
+    }
+
+    #[test]
+    fn rename () {
+        let mut kernel = MockKernel::new(RenameFS(Vec::new())).unwrap();
+        kernel.rename(1, "a", 1, "b", RenameFlags::default()).unwrap();
+        kernel.rename(1, "a", 1, "b", RenameFlags::EXCHANGE).unwrap();
+        assert_eq!(kernel.rename(1, "a", 1, "b", RenameFlags::WHITEOUT).unwrap_err(), EINVAL);
+        assert_eq!(kernel.filesystem().0, [RenameFlags::default(), RenameFlags::EXCHANGE, RenameFlags::WHITEOUT]);
+    }
+
+    /// Filesystem in writeback cache mode that remembers times of setattrs and
+    /// flags of writes
+    #[derive(Default)]
+    struct WritebackFS {
+        times: Vec<(Option<TimeOrNow>, Option<TimeOrNow>, Option<Timespec>)>,
+        writes: Vec<(WriteFlags, Option<u64>)>,
+    }
+
+    impl Filesystem for WritebackFS {
+        fn init (&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+            config.add_capabilities(FUSE_WRITEBACK_CACHE).map_err(|_| ENOSYS)
+        }
+
+        fn setattr (&mut self, _req: &Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<Timespec>, _fh: Option<u64>, _kill_suidgid: bool, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+            self.times.push((atime, mtime, ctime));
+            reply.attr(&TTL, &attr(ino, FileType::RegularFile, 0));
+        }
+
+        fn write (&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, data: &[u8], write_flags: WriteFlags, lock_owner: Option<u64>, reply: ReplyWrite) {
+            self.writes.push((write_flags, lock_owner));
+            reply.written(data.len() as u32);
+        }
+    }
+
+    #[test]
+    fn writeback_cache () {
+        let mut kernel = MockKernel::new(WritebackFS::default()).unwrap();
+        assert!(kernel.capabilities() & FUSE_WRITEBACK_CACHE != 0);
+        let time = Timespec::new(5, 6);
+        kernel.setattr(2, None, None, None, None, Some(TimeOrNow::Now), Some(TimeOrNow::SpecificTime(time)), Some(time), None).unwrap();
+        kernel.setattr(2, None, None, None, None, None, Some(TimeOrNow::Now), None, None).unwrap();
+        assert_eq!(kernel.filesystem().times, [
+            (Some(TimeOrNow::Now), Some(TimeOrNow::SpecificTime(time)), Some(time)),
+            (None, Some(TimeOrNow::Now), None),
+        ]);
+        assert_eq!(kernel.write(2, 0, 0, b"foo", WriteFlags::CACHE, None), Ok(3));
+        assert_eq!(kernel.write(2, 0, 3, b"bar", WriteFlags::default(), Some(42)), Ok(3));
+        assert_eq!(kernel.filesystem().writes, [(WriteFlags::CACHE, None), (WriteFlags::LOCKOWNER, Some(42))]);
+    }
+
+    /// Filesystem in splice mode that stores data in a file and remembers whether
+    /// the data of writes was in a pipe
+    struct SpliceFS {
+        file: fs::File,
+        spliced: Vec<bool>,
+    }
+
+    impl Filesystem for SpliceFS {
+        fn init (&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+            config.set_max_write(0x10000).map_err(|_| EINVAL)?;
+            config.add_capabilities(FUSE_SPLICE_READ | FUSE_SPLICE_WRITE | FUSE_SPLICE_MOVE).map_err(|_| ENOSYS)
+        }
+
+        fn read (&mut self, _req: &Request, _ino: u64, _fh: u64, offset: u64, size: u32, reply: ReplyData) {
+            reply.from_fd(self.file.as_raw_fd(), offset, size as usize);
+        }
+
+        fn write_buf (&mut self, _req: &Request, _ino: u64, _fh: u64, offset: u64, data: &WriteBuf, _write_flags: WriteFlags, _lock_owner: Option<u64>, reply: ReplyWrite) {
+            self.spliced.push(data.as_slice().is_none());
+            match data.write_to(self.file.as_raw_fd(), offset) {
+                Ok(size) => reply.written(size as u32),
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn splice () {
+        let path = env::temp_dir().join(format!("rust-fuse-splice-{}", process::id()));
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut kernel = MockKernel::new(SpliceFS { file: file, spliced: Vec::new() }).unwrap();
+        let data: Vec<u8> = (0..0x8000).map(|i| i as u8).collect();
+        assert_eq!(kernel.write(2, 0, 0, &data, WriteFlags::default(), None), Ok(0x8000));
+        // Small writes aren't spliced
+        assert_eq!(kernel.write(2, 0, 0x8000, b"foo", WriteFlags::default(), None), Ok(3));
+        assert_eq!(kernel.filesystem().spliced, [true, false]);
+        assert_eq!(kernel.read(2, 0, 0x10, 0x1000).unwrap(), &data[0x10..0x1010]);
+        // Reads are short at the end of the file
+        assert_eq!(kernel.read(2, 0, 0x7ffe, 0x1000).unwrap(), [0xfe, 0xff, b'f', b'o', b'o']);
+        assert_eq!(kernel.read(2, 0, 0x9000, 0x1000).unwrap(), []);
+    }
+
+    #[test]
+    fn batch_forget () {
+        let mut kernel = MockKernel::new(ForgetFS(Vec::new())).unwrap();
+        kernel.forget(2, 1);
+        kernel.batch_forget(&[(3, 2), (4, 5)]);
+        // Count exceeds the number of nodes
+        let arg = fuse_batch_forget_in { count: 1000, dummy: 0 };
+        kernel.send(FUSE_BATCH_FORGET, 0, &[&arg_bytes(&arg), &arg_bytes(&fuse_forget_one { nodeid: 6, nlookup: 1 })]);
+        assert_eq!(kernel.filesystem().0, [(2, 1), (3, 2), (4, 5)]);
+    }
+}
@@ -0,0 +1,329 @@
+//!
+//! Mounting and unmounting without libfuse (Linux only). If we're privileged,
+//! /dev/fuse is opened and mounted directly using mount(2). Otherwise, the
+//! setuid fusermount helper mounts it and passes the fd back to us over a
+//! unix socket.
+//!
+
+#![cfg_attr(feature = "libfuse", allow(dead_code))]
+
+use std::{io, mem, ptr};
+use std::ffi::{CString, OsStr, OsString};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command};
+use libc::{self, c_int, c_void};
+use mount_options::{MountOption, MountOptions, UnmountFlags, escape};
+
+/// Names of the fusermount helper, in order of preference
+const FUSERMOUNT_NAMES: [&str; 2] = ["fusermount3", "fusermount"];
+
+/// Environment variable that tells fusermount which fd to send the /dev/fuse fd to
+const FUSERMOUNT_COMMFD_ENV: &str = "_FUSE_COMMFD";
+
+/// A mounted /dev/fuse fd
+#[derive(Debug)]
+pub struct Mount {
+    /// Fd of the FUSE connection
+    pub fd: c_int,
+    /// The fusermount helper if it stays around to unmount the filesystem when
+    /// its socket is closed (auto_unmount option)
+    pub helper: Option<Helper>,
+}
+
+/// A running fusermount helper that unmounts the filesystem once its socket is closed
+#[derive(Debug)]
+pub struct Helper {
+    /// Our end of the socket to the helper
+    sock: UnixStream,
+    /// The helper process
+    child: Child,
+}
+
+impl Helper {
+    /// Close the socket to the helper, which lets it unmount the filesystem (unless
+    /// that already happened) and exit, and wait for it to exit
+    pub fn finish (self) {
+        let Helper { sock, mut child } = self;
+        drop(sock);
+        if let Err(err) = child.wait() {
+            warn!("Failed to wait for fusermount: {}", err);
+        }
+    }
+}
+
+/// Mount options split into what the kernel, mount(2) and fusermount need
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    /// Mount flags (MS_*)
+    flags: libc::c_ulong,
+    /// Options that are passed to the kernel driver in the mount data
+    kernel: Vec<String>,
+    /// Name of the mount source
+    fsname: Option<String>,
+    /// Filesystem subtype
+    subtype: Option<String>,
+    /// Options that require the fusermount helper
+    auto_unmount: bool,
+}
+
+impl Options {
+    /// Split the given mount options
+    fn new (mount_options: &MountOptions) -> Options {
+        let mut options = Options { flags: libc::MS_NOSUID | libc::MS_NODEV, ..Options::default() };
+        for option in mount_options.options() {
+            options.add(option);
+        }
+        options
+    }
+
+    /// Add a single option
+    fn add (&mut self, option: &MountOption) {
+        match *option {
+            MountOption::RO => self.flags |= libc::MS_RDONLY,
+            MountOption::RW => self.flags &= !libc::MS_RDONLY,
+            MountOption::NoSuid => self.flags |= libc::MS_NOSUID,
+            MountOption::Suid => self.flags &= !libc::MS_NOSUID,
+            MountOption::NoDev => self.flags |= libc::MS_NODEV,
+            MountOption::Dev => self.flags &= !libc::MS_NODEV,
+            MountOption::NoExec => self.flags |= libc::MS_NOEXEC,
+            MountOption::Exec => self.flags &= !libc::MS_NOEXEC,
+            MountOption::NoAtime => self.flags |= libc::MS_NOATIME,
+            MountOption::Atime => self.flags &= !libc::MS_NOATIME,
+            MountOption::Sync => self.flags |= libc::MS_SYNCHRONOUS,
+            MountOption::Async => self.flags &= !libc::MS_SYNCHRONOUS,
+            MountOption::DirSync => self.flags |= libc::MS_DIRSYNC,
+            MountOption::AutoUnmount => self.auto_unmount = true,
+            // The kernel doesn't know allow_root, so it allows everyone and the
+            // session denies requests of users other than the owner and root
+            MountOption::AllowRoot => self.kernel.push("allow_other".to_string()),
+            MountOption::FSName(ref name) => self.fsname = Some(name.clone()),
+            MountOption::Subtype(ref name) => self.subtype = Some(name.clone()),
+            MountOption::Custom(ref option) => self.kernel.push(option.clone()),
+            MountOption::AllowOther | MountOption::DefaultPermissions |
+            MountOption::MaxRead(_) | MountOption::BlkSize(_) => self.kernel.push(option.to_string()),
+        }
+    }
+
+    /// Returns the options as a comma separated list for fusermount
+    fn to_fusermount (&self) -> String {
+        let mut list: Vec<String> = Vec::new();
+        for &(flag, set, unset) in &[(libc::MS_RDONLY, "ro", "rw"), (libc::MS_NOSUID, "nosuid", "suid"),
+                                     (libc::MS_NODEV, "nodev", "dev"), (libc::MS_NOEXEC, "noexec", "exec"),
+                                     (libc::MS_NOATIME, "noatime", "atime"), (libc::MS_SYNCHRONOUS, "sync", "async")] {
+            list.push(if self.flags & flag != 0 { set } else { unset }.to_string());
+        }
+        if self.flags & libc::MS_DIRSYNC != 0 { list.push("dirsync".to_string()); }
+        if self.auto_unmount { list.push("auto_unmount".to_string()); }
+        if let Some(ref fsname) = self.fsname { list.push(MountOption::FSName(fsname.clone()).to_string()); }
+        if let Some(ref subtype) = self.subtype { list.push(MountOption::Subtype(subtype.clone()).to_string()); }
+        list.extend(self.kernel.iter().map(|option| escape(option)));
+        list.join(",")
+    }
+}
+
+/// Mount a FUSE filesystem to the given mount point and return the fd of the new
+/// connection. The mount point must be an absolute path.
+pub fn mount (mountpoint: &Path, mount_options: &MountOptions) -> io::Result<Mount> {
+    let options = Options::new(mount_options);
+    // auto_unmount needs the helper to stay around, so don't try to mount directly
+    if !options.auto_unmount {
+        match mount_direct(mountpoint, &options) {
+            Ok(fd) => return Ok(Mount { fd: fd, helper: None }),
+            Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => (),
+            Err(err) => return Err(err),
+        }
+        debug!("Not permitted to mount {}, using fusermount", mountpoint.display());
+    }
+    mount_fusermount(mountpoint, &options)
+}
+
+/// Open /dev/fuse and mount it using mount(2), which requires privileges
+fn mount_direct (mountpoint: &Path, options: &Options) -> io::Result<c_int> {
+    let fd = unsafe { libc::open(b"/dev/fuse\0".as_ptr() as *const libc::c_char, libc::O_RDWR | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut data = vec![
+        format!("fd={}", fd),
+        format!("rootmode={:o}", libc::S_IFDIR),
+        format!("user_id={}", unsafe { libc::getuid() }),
+        format!("group_id={}", unsafe { libc::getgid() }),
+    ];
+    data.extend(options.kernel.iter().cloned());
+    let source = options.fsname.clone().unwrap_or_else(|| "rust-fuse".to_string());
+    let fstype = match options.subtype {
+        Some(ref subtype) => format!("fuse.{}", subtype),
+        None => "fuse".to_string(),
+    };
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let source = CString::new(source)?;
+    let fstype = CString::new(fstype)?;
+    let data = CString::new(data.join(","))?;
+    let rc = unsafe { libc::mount(source.as_ptr(), mnt.as_ptr(), fstype.as_ptr(), options.flags, data.as_ptr() as *const c_void) };
+    if rc < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd); }
+        Err(err)
+    } else {
+        Ok(fd)
+    }
+}
+
+/// Spawn fusermount with the given arguments. The given fd (if any) is passed to
+/// fusermount as communication socket. It may be close-on-exec, only the spawned
+/// fusermount inherits it.
+fn spawn_fusermount (args: &[&OsStr], commfd: Option<c_int>) -> io::Result<Child> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "fusermount not found");
+    for name in &FUSERMOUNT_NAMES {
+        let mut cmd = Command::new(name);
+        cmd.args(args);
+        if let Some(fd) = commfd {
+            cmd.env(FUSERMOUNT_COMMFD_ENV, fd.to_string());
+            // Runs in the forked child, fcntl is async-signal-safe
+            unsafe {
+                cmd.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+                    rc if rc < 0 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                });
+            }
+        }
+        match cmd.spawn() {
+            Ok(child) => return Ok(child),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Run fusermount with the given arguments and wait for it to succeed
+fn fusermount (args: &[&OsStr], commfd: Option<c_int>) -> io::Result<()> {
+    let status = spawn_fusermount(args, commfd)?.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("fusermount failed: {}", status)))
+    }
+}
+
+/// Mount using the fusermount helper and receive the fd from it
+fn mount_fusermount (mountpoint: &Path, options: &Options) -> io::Result<Mount> {
+    // Only the helper's end of the socket pair is inherited by the helper. Both ends
+    // are created close-on-exec, so other threads that fork+exec can't inherit them.
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (sock, helper_sock) = unsafe { (UnixStream::from_raw_fd(fds[0]), UnixStream::from_raw_fd(fds[1])) };
+    let opts = OsString::from(options.to_fusermount());
+    let args = [OsStr::new("-o"), &opts, OsStr::new("--"), mountpoint.as_os_str()];
+    let child = if options.auto_unmount {
+        // With auto_unmount, the helper keeps running until our end of the socket
+        // is closed, so it must not be waited for yet
+        Some(spawn_fusermount(&args, Some(fds[1]))?)
+    } else {
+        fusermount(&args, Some(fds[1]))?;
+        None
+    };
+    drop(helper_sock);
+    let fd = match receive_fd(&sock) {
+        Ok(fd) => fd,
+        Err(err) => {
+            if let Some(child) = child { Helper { sock: sock, child: child }.finish(); }
+            return Err(err);
+        },
+    };
+    Ok(Mount { fd: fd, helper: child.map(|child| Helper { sock: sock, child: child }) })
+}
+
+/// Receive a fd that was sent over the given unix socket (SCM_RIGHTS)
+fn receive_fd (sock: &UnixStream) -> io::Result<c_int> {
+    use std::os::unix::io::AsRawFd;
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr() as *mut c_void, iov_len: byte.len() };
+    let mut cmsg_buf = [0u8; 64];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<c_int>() as u32) } as _;
+    let rc = loop {
+        let rc = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if rc < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted { continue; }
+        break rc;
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if rc == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "fusermount didn't send a fd"));
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_level != libc::SOL_SOCKET || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "fusermount sent an invalid message"));
+        }
+        Ok(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int))
+    }
+}
+
+/// Unmount a FUSE filesystem using the given flags. If we're not privileged,
+/// fusermount is used, which can't force unmounting.
+pub fn unmount (mountpoint: &Path, flags: UnmountFlags) -> io::Result<()> {
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let mut umount_flags = 0;
+    if flags.lazy { umount_flags |= libc::MNT_DETACH; }
+    if flags.force { umount_flags |= libc::MNT_FORCE; }
+    let rc = unsafe { libc::umount2(mnt.as_ptr(), umount_flags) };
+    if rc == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EPERM) || flags.force {
+        // fusermount can't force unmounting, so don't pretend it worked
+        return Err(err);
+    }
+    let mut args = vec![OsStr::new("-u"), OsStr::new("-q")];
+    if flags.lazy { args.push(OsStr::new("-z")); }
+    args.push(OsStr::new("--"));
+    args.push(mountpoint.as_os_str());
+    fusermount(&args, None)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Options;
+    use libc;
+    use mount_options::{MountOption, MountOptions};
+
+    #[test]
+    fn split_options () {
+        let options = Options::new(&MountOptions::new()
+            .add(MountOption::RO)
+            .add(MountOption::FSName("foo".to_string()))
+            .add(MountOption::AllowOther)
+            .add(MountOption::Subtype("bar".to_string()))
+            .add(MountOption::Dev));
+        assert_eq!(options.flags, libc::MS_RDONLY | libc::MS_NOSUID);
+        assert_eq!(options.kernel, vec!["allow_other".to_string()]);
+        assert_eq!(options.fsname, Some("foo".to_string()));
+        assert_eq!(options.subtype, Some("bar".to_string()));
+        assert!(!options.auto_unmount);
+    }
+
+    #[test]
+    fn fusermount_options () {
+        let options = Options::new(&MountOptions::new()
+            .add(MountOption::NoExec)
+            .add(MountOption::AutoUnmount)
+            .add(MountOption::FSName("a,b".to_string()))
+            .add(MountOption::DefaultPermissions));
+        assert_eq!(options.to_fusermount(), "rw,nosuid,nodev,noexec,atime,async,auto_unmount,fsname=a\\,b,default_permissions");
+    }
+}
@@ -0,0 +1,165 @@
+//!
+//! Options for mounting and unmounting a filesystem. Options are given as typed
+//! values and are serialized to whatever the mount implementation expects.
+//!
+
+use std::ffi::OsString;
+use std::fmt;
+
+/// Mount option
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountOption {
+    /// Name of the mount source (shown e.g. by mount and df)
+    FSName(String),
+    /// Filesystem subtype (the filesystem type is shown as "fuse.<subtype>")
+    Subtype(String),
+    /// Allow all users to access the filesystem
+    AllowOther,
+    /// Allow the mounting user and root to access the filesystem. The kernel only
+    /// knows allow_other, so the session denies requests of other users (EACCES).
+    AllowRoot,
+    /// Let the kernel check permissions based on the file mode
+    DefaultPermissions,
+    /// Mount read-only
+    RO,
+    /// Mount read-write
+    RW,
+    /// Interpret character and block special devices
+    Dev,
+    /// Don't interpret character and block special devices
+    NoDev,
+    /// Honor set-user-id and set-group-id bits
+    Suid,
+    /// Ignore set-user-id and set-group-id bits
+    NoSuid,
+    /// Allow executing binaries
+    Exec,
+    /// Don't allow executing binaries
+    NoExec,
+    /// Update access times
+    Atime,
+    /// Don't update access times
+    NoAtime,
+    /// All I/O is done synchronously
+    Sync,
+    /// All I/O is done asynchronously
+    Async,
+    /// All directory updates are done synchronously
+    DirSync,
+    /// Unmount automatically when the filesystem process exits (requires fusermount)
+    AutoUnmount,
+    /// Max size of read requests
+    MaxRead(u32),
+    /// Block size of the filesystem (for block device based filesystems)
+    BlkSize(u32),
+    /// Any other option, passed as is
+    Custom(String),
+}
+
+impl fmt::Display for MountOption {
+    /// Formats the option as used in a comma separated option list
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let option = match *self {
+            MountOption::FSName(ref name) => format!("fsname={}", escape(name)),
+            MountOption::Subtype(ref name) => format!("subtype={}", escape(name)),
+            MountOption::AllowOther => "allow_other".to_string(),
+            MountOption::AllowRoot => "allow_root".to_string(),
+            MountOption::DefaultPermissions => "default_permissions".to_string(),
+            MountOption::RO => "ro".to_string(),
+            MountOption::RW => "rw".to_string(),
+            MountOption::Dev => "dev".to_string(),
+            MountOption::NoDev => "nodev".to_string(),
+            MountOption::Suid => "suid".to_string(),
+            MountOption::NoSuid => "nosuid".to_string(),
+            MountOption::Exec => "exec".to_string(),
+            MountOption::NoExec => "noexec".to_string(),
+            MountOption::Atime => "atime".to_string(),
+            MountOption::NoAtime => "noatime".to_string(),
+            MountOption::Sync => "sync".to_string(),
+            MountOption::Async => "async".to_string(),
+            MountOption::DirSync => "dirsync".to_string(),
+            MountOption::AutoUnmount => "auto_unmount".to_string(),
+            MountOption::MaxRead(size) => format!("max_read={}", size),
+            MountOption::BlkSize(size) => format!("blksize={}", size),
+            MountOption::Custom(ref option) => escape(option),
+        };
+        f.write_str(&option)
+    }
+}
+
+/// Escape commas and backslashes in an option value (like libfuse's option parser expects)
+pub fn escape (value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,")
+}
+
+/// Builder for a list of mount options
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    options: Vec<MountOption>,
+}
+
+impl MountOptions {
+    /// Create an empty list of mount options
+    pub fn new () -> MountOptions {
+        MountOptions::default()
+    }
+
+    /// Add the given option. Later options override earlier ones.
+    pub fn add (mut self, option: MountOption) -> MountOptions {
+        self.options.push(option);
+        self
+    }
+
+    /// Returns the options
+    pub fn options (&self) -> &[MountOption] {
+        &self.options
+    }
+
+    /// Returns the options as command line arguments for libfuse
+    pub fn to_args (&self) -> Vec<OsString> {
+        if self.options.is_empty() {
+            return Vec::new();
+        }
+        let list: Vec<String> = self.options.iter().map(|o| o.to_string()).collect();
+        vec![OsString::from("-o"), OsString::from(list.join(","))]
+    }
+}
+
+impl<'a> From<&'a [MountOption]> for MountOptions {
+    fn from (options: &'a [MountOption]) -> MountOptions {
+        MountOptions { options: options.to_vec() }
+    }
+}
+
+/// Flags for unmounting a filesystem
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UnmountFlags {
+    /// Detach the filesystem immediately and clean up once it isn't busy anymore
+    /// (MNT_DETACH, fusermount -z). Not supported on OS X and BSD.
+    pub lazy: bool,
+    /// Unmount even if the filesystem is busy or doesn't respond (MNT_FORCE).
+    /// Requires privileges on Linux.
+    pub force: bool,
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use super::{MountOption, MountOptions};
+
+    #[test]
+    fn empty_args () {
+        assert!(MountOptions::new().to_args().is_empty());
+    }
+
+    #[test]
+    fn option_args () {
+        let options = MountOptions::new()
+            .add(MountOption::FSName("foo,bar".to_string()))
+            .add(MountOption::RO)
+            .add(MountOption::MaxRead(4096))
+            .add(MountOption::Custom("user_xattr".to_string()));
+        assert_eq!(options.to_args(), vec![OsString::from("-o"), OsString::from("fsname=foo\\,bar,ro,max_read=4096,user_xattr")]);
+    }
+}
@@ -0,0 +1,234 @@
+//!
+//! Notifications are messages the filesystem sends to the kernel on its own
+//! account, e.g. to invalidate cached data if the contents of the filesystem
+//! changed behind the kernel's back. A notifier can be obtained from a session
+//! and can safely be sent to other threads.
+//!
+
+use std::io;
+use std::collections::HashMap;
+use std::convert::AsRef;
+use std::ffi::OsStr;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use channel::GuardedSender;
+use fuse::{fuse_out_header, fuse_notify_code};
+use fuse::{fuse_notify_inval_inode_out, fuse_notify_inval_entry_out, fuse_notify_delete_out};
+use fuse::{fuse_notify_store_out, fuse_notify_retrieve_out, fuse_notify_poll_wakeup_out};
+use fuse::fuse_notify_code::*;
+use reply::as_bytes;
+
+/// Bookkeeping of retrieve notifications the kernel didn't reply to yet
+#[derive(Debug)]
+struct RetrievalState {
+    /// Unique id to use for the next retrieve notification
+    next_unique: u64,
+    /// Receivers of retrieved data, by unique id of the retrieve notification
+    pending: HashMap<u64, Sender<Vec<u8>>>,
+}
+
+/// Pending retrieve notifications of a session. The kernel replies to a retrieve
+/// notification with a NOTIFY_REPLY request which is routed back to the notifier
+/// that sent it.
+#[derive(Clone, Debug)]
+pub struct Retrievals {
+    state: Arc<Mutex<RetrievalState>>,
+}
+
+impl Retrievals {
+    /// Create a new, empty set of pending retrievals
+    pub fn new () -> Retrievals {
+        Retrievals { state: Arc::new(Mutex::new(RetrievalState { next_unique: 1, pending: HashMap::new() })) }
+    }
+
+    /// Register a new retrieval. Returns its unique id and the receiver of the data.
+    fn register (&self) -> (u64, Receiver<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        let unique = state.next_unique;
+        state.next_unique += 1;
+        let (tx, rx) = channel();
+        state.pending.insert(unique, tx);
+        (unique, rx)
+    }
+
+    /// Remove a retrieval without completing it
+    fn unregister (&self, unique: u64) {
+        self.state.lock().unwrap().pending.remove(&unique);
+    }
+
+    /// Complete the retrieval with the given unique id by passing the retrieved data
+    /// to its receiver. Returns false if there's no such retrieval.
+    pub fn complete (&self, unique: u64, data: &[u8]) -> bool {
+        match self.state.lock().unwrap().pending.remove(&unique) {
+            // The receiver may have been dropped already, which is fine
+            Some(tx) => { let _ = tx.send(data.to_vec()); true },
+            None => false,
+        }
+    }
+}
+
+/// Handle for sending notifications to the kernel
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    /// Channel sender to send notifications with
+    sender: GuardedSender,
+    /// Pending retrievals of the session
+    retrievals: Retrievals,
+}
+
+impl Notifier {
+    /// Create a new notifier that sends to the given channel
+    pub fn new (sender: GuardedSender, retrievals: Retrievals) -> Notifier {
+        Notifier { sender: sender, retrievals: retrievals }
+    }
+
+    /// Send a notification with the given code, argument struct and additional data
+    fn send<T> (&self, code: fuse_notify_code, arg: &T, data: &[&[u8]]) -> io::Result<()> {
+        let len = data.iter().fold(0, |l, b| l + b.len());
+        let header = fuse_out_header {
+            len: (size_of::<fuse_out_header>() + size_of::<T>() + len) as u32,
+            error: code as i32,
+            unique: 0,
+        };
+        as_bytes(&header, |headerbytes| {
+            as_bytes(arg, |argbytes| {
+                let mut sendbytes = headerbytes.to_vec();
+                sendbytes.extend(argbytes);
+                sendbytes.extend(data);
+                self.sender.send(&sendbytes)
+            })
+        })
+    }
+
+    /// Invalidate cached attributes and data of the given inode. Data is invalidated
+    /// in the range starting at offset with the given length. A negative offset
+    /// invalidates only the attributes, a length of 0 invalidates all data to the
+    /// end of the file.
+    pub fn inval_inode (&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        debug!("NOTIFY INVAL_INODE ino {:#018x}, offset {}, len {}", ino, offset, len);
+        let arg = fuse_notify_inval_inode_out { ino: ino, off: offset, len: len };
+        self.send(FUSE_NOTIFY_INVAL_INODE, &arg, &[])
+    }
+
+    /// Invalidate the cached directory entry with the given name in the given parent
+    /// directory, and the attributes of the parent directory.
+    pub fn inval_entry<T: AsRef<OsStr>> (&self, parent: u64, name: T) -> io::Result<()> {
+        let name = name.as_ref();
+        debug!("NOTIFY INVAL_ENTRY parent {:#018x}, name {:?}", parent, name);
+        let arg = fuse_notify_inval_entry_out { parent: parent, namelen: name.len() as u32, padding: 0 };
+        self.send(FUSE_NOTIFY_INVAL_ENTRY, &arg, &[name.as_bytes(), &[0]])
+    }
+
+    /// Notify the kernel that the directory entry with the given name in the given
+    /// parent directory was deleted. Like inval_entry, but if the entry refers to the
+    /// given child inode, the kernel also removes the dentry (e.g. to notify inotify
+    /// watchers).
+    pub fn delete<T: AsRef<OsStr>> (&self, parent: u64, child: u64, name: T) -> io::Result<()> {
+        let name = name.as_ref();
+        debug!("NOTIFY DELETE parent {:#018x}, child {:#018x}, name {:?}", parent, child, name);
+        let arg = fuse_notify_delete_out { parent: parent, child: child, namelen: name.len() as u32, padding: 0 };
+        self.send(FUSE_NOTIFY_DELETE, &arg, &[name.as_bytes(), &[0]])
+    }
+
+    /// Store the given data in the kernel's page cache of the given inode, starting
+    /// at the given offset. The file size is extended if necessary.
+    pub fn store (&self, ino: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        debug!("NOTIFY STORE ino {:#018x}, offset {}, size {}", ino, offset, data.len());
+        let arg = fuse_notify_store_out { nodeid: ino, offset: offset, size: data.len() as u32, padding: 0 };
+        self.send(FUSE_NOTIFY_STORE, &arg, &[data])
+    }
+
+    /// Retrieve up to size bytes of the kernel's page cache of the given inode, starting
+    /// at the given offset. The kernel replies asynchronously, so the data is delivered
+    /// through the returned receiver once it arrives. The data ends at the first page
+    /// that isn't cached.
+    pub fn retrieve (&self, ino: u64, offset: u64, size: u32) -> io::Result<Receiver<Vec<u8>>> {
+        let (unique, rx) = self.retrievals.register();
+        debug!("NOTIFY RETRIEVE({}) ino {:#018x}, offset {}, size {}", unique, ino, offset, size);
+        let arg = fuse_notify_retrieve_out { notify_unique: unique, nodeid: ino, offset: offset, size: size, padding: 0 };
+        match self.send(FUSE_NOTIFY_RETRIEVE, &arg, &[]) {
+            Ok(()) => Ok(rx),
+            Err(err) => { self.retrievals.unregister(unique); Err(err) },
+        }
+    }
+
+    /// Wake up pollers of the file that was polled with the given kernel poll handle
+    pub fn poll (&self, kh: u64) -> io::Result<()> {
+        debug!("NOTIFY POLL kh {}", kh);
+        let arg = fuse_notify_poll_wakeup_out { kh: kh };
+        self.send(FUSE_NOTIFY_POLL, &arg, &[])
+    }
+}
+
+/// Handle for waking up pollers of a file. It's passed to the poll operation and
+/// can be kept by the filesystem (and sent to other threads) to notify the kernel
+/// once the file becomes ready.
+#[derive(Clone, Debug)]
+pub struct PollHandle {
+    /// Kernel poll handle
+    kh: u64,
+    /// Notifier to send the wakeup with
+    notifier: Notifier,
+}
+
+impl PollHandle {
+    /// Create a new poll handle for the given kernel poll handle
+    pub fn new (kh: u64, notifier: Notifier) -> PollHandle {
+        PollHandle { kh: kh, notifier: notifier }
+    }
+
+    /// Returns the kernel poll handle
+    pub fn kh (&self) -> u64 {
+        self.kh
+    }
+
+    /// Wake up pollers of the file. Fails with EBADF if the session ended.
+    pub fn notify (&self) -> io::Result<()> {
+        self.notifier.poll(self.kh)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use libc::EBADF;
+    use channel::Channel;
+    use super::{Notifier, PollHandle, Retrievals};
+
+    #[test]
+    fn retrievals () {
+        let retrievals = Retrievals::new();
+        let (unique1, rx1) = retrievals.register();
+        let (unique2, rx2) = retrievals.register();
+        assert!(unique1 != unique2);
+        assert!(retrievals.complete(unique2, &[1, 2, 3]));
+        assert!(!retrievals.complete(unique2, &[4]));
+        assert_eq!(rx2.recv().unwrap(), vec![1, 2, 3]);
+        retrievals.unregister(unique1);
+        assert!(!retrievals.complete(unique1, &[]));
+        assert!(rx1.recv().is_err());
+    }
+
+    #[test]
+    fn poll_wakeup () {
+        let (ch, session_ch) = Channel::pair().unwrap();
+        let ph = PollHandle::new(0x1234, Notifier::new(session_ch.guarded_sender(), Retrievals::new()));
+        assert_eq!(ph.kh(), 0x1234);
+        ph.notify().unwrap();
+        let mut buffer = Vec::with_capacity(64);
+        ch.receive(&mut buffer).unwrap();
+        assert_eq!(buffer, [0x18, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn closed_channel () {
+        let (_ch, session_ch) = Channel::pair().unwrap();
+        let notifier = Notifier::new(session_ch.guarded_sender(), Retrievals::new());
+        drop(session_ch);
+        assert_eq!(notifier.inval_inode(1, 0, 0).unwrap_err().raw_os_error(), Some(EBADF));
+        assert_eq!(notifier.store(1, 0, &[1, 2, 3]).unwrap_err().raw_os_error(), Some(EBADF));
+    }
+}
@@ -8,31 +8,68 @@
 //! exactly once).
 //!
 
-use std::{mem, ptr, slice};
+use std::{io, mem, ptr, slice};
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::fmt;
 use std::marker::PhantomData;
+use std::mem::{size_of, size_of_val};
 use std::os::unix::ffi::OsStrExt;
-use libc::{c_int, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK, EIO};
+use std::os::unix::io::RawFd;
+use libc::{self, c_int, c_void, off_t, size_t, S_IFIFO, S_IFCHR, S_IFBLK, S_IFDIR, S_IFREG, S_IFLNK, EINTR, EIO};
 use time::Timespec;
 use fuse::{fuse_attr, fuse_kstatfs, fuse_file_lock, fuse_entry_out, fuse_attr_out};
 use fuse::{fuse_open_out, fuse_write_out, fuse_statfs_out, fuse_lk_out, fuse_bmap_out};
-use fuse::fuse_getxattr_out;
+use fuse::{fuse_getxattr_out, fuse_lseek_out, fuse_ioctl_out, fuse_ioctl_iovec, fuse_poll_out};
+use fuse::consts::{FUSE_IOCTL_RETRY, FUSE_IOCTL_MAX_IOV};
 #[cfg(target_os = "macos")]
 use fuse::fuse_getxtimes_out;
-use fuse::{fuse_out_header, fuse_dirent};
-use {FileType, FileAttr};
+use fuse::{fuse_out_header, fuse_dirent, fuse_direntplus};
+use {FileType, FileAttr, IoctlFlags};
 
 /// Generic reply callback to send data
 pub trait ReplySender: Send + 'static {
     /// Send data.
     fn send(&self, data: &[&[u8]]);
+
+    /// Send the given header followed by up to len bytes of the given file at the
+    /// given offset. The length in the header is increased by the number of bytes
+    /// that were read. If reading the file fails, nothing is sent. Senders that
+    /// support splicing send the data without copying it.
+    fn send_fd (&self, header: fuse_out_header, fd: RawFd, offset: u64, len: usize) -> io::Result<()> {
+        send_fd_copy(self, header, fd, offset, len)
+    }
+
+    /// Send the given header followed by the given data (the length in the header
+    /// includes the data). The sender takes ownership of the data, so it can keep it
+    /// without copying, e.g. to send it later.
+    fn send_owned (&self, header: fuse_out_header, data: Vec<u8>) {
+        as_bytes(&header, |headerbytes| self.send(&[headerbytes[0], &data]));
+    }
+}
+
+/// Send the given header followed by up to len bytes of the given file at the given
+/// offset by reading the data into memory
+pub fn send_fd_copy<S: ReplySender + ?Sized> (sender: &S, mut header: fuse_out_header, fd: RawFd, offset: u64, len: usize) -> io::Result<()> {
+    let mut data: Vec<u8> = Vec::with_capacity(len);
+    while data.len() < len {
+        let rc = unsafe { libc::pread(fd, data.as_mut_ptr().offset(data.len() as isize) as *mut c_void, (len - data.len()) as size_t, (offset + data.len() as u64) as off_t) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(EINTR) { continue; }
+            return Err(err);
+        }
+        if rc == 0 { break; }
+        unsafe { data.set_len(data.len() + rc as usize); }
+    }
+    header.len += data.len() as u32;
+    as_bytes(&header, |headerbytes| sender.send(&[headerbytes[0], &data]));
+    Ok(())
 }
 
-impl fmt::Debug for Box<ReplySender> {
+impl fmt::Debug for Box<dyn ReplySender> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "Box<ReplySender>")
+        write!(f, "Box<dyn ReplySender>")
     }
 }
 
@@ -43,8 +80,8 @@ pub trait Reply {
 }
 
 /// Serialize an arbitrary type to bytes (memory copy, useful for fuse_*_out types)
-fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U> (data: &T, f: F) -> U {
-    let len = mem::size_of::<T>();
+pub fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U> (data: &T, f: F) -> U {
+    let len = size_of::<T>();
     match len {
         0 => f(&[]),
         len => {
@@ -91,6 +128,8 @@ fn fuse_attr_from_attr (attr: &FileAttr) -> fuse_attr {
         gid: attr.gid,
         rdev: attr.rdev,
         flags: attr.flags,
+        blksize: attr.blksize,
+        padding: 0,
     }
 }
 
@@ -112,6 +151,8 @@ fn fuse_attr_from_attr (attr: &FileAttr) -> fuse_attr {
         uid: attr.uid,
         gid: attr.gid,
         rdev: attr.rdev,
+        blksize: attr.blksize,
+        flags: 0,
     }
 }
 
@@ -123,7 +164,7 @@ pub struct ReplyRaw<T> {
     /// Unique id of the request to reply to
     unique: u64,
     /// Closure to call for sending the reply
-    sender: Option<Box<ReplySender>>,
+    sender: Option<Box<dyn ReplySender>>,
     /// Marker for being able to have T on this struct (which enforces
     /// reply types to send the correct type of data)
     marker: PhantomData<T>,
@@ -143,18 +184,48 @@ impl<T> ReplyRaw<T> {
         assert!(self.sender.is_some());
         let len = bytes.iter().fold(0, |l, b| { l +  b.len()});
         let header = fuse_out_header {
-            len: (mem::size_of::<fuse_out_header>() + len) as u32,
+            len: (size_of::<fuse_out_header>() + len) as u32,
             error: -err,
             unique: self.unique,
         };
         as_bytes(&header, |headerbytes| {
             let sender = self.sender.take().unwrap();
-            let mut sendbytes = headerbytes.to_vec();
-            sendbytes.extend(bytes);
+            let mut sendbytes = Vec::with_capacity(headerbytes.len() + bytes.len());
+            sendbytes.extend_from_slice(headerbytes);
+            sendbytes.extend_from_slice(bytes);
             sender.send(&sendbytes);
         });
     }
 
+    /// Reply to a request with up to len bytes of the given file at the given offset.
+    /// If reading the file fails, the request is replied with the error instead.
+    fn send_fd (&mut self, fd: RawFd, offset: u64, len: usize) {
+        assert!(self.sender.is_some());
+        let header = fuse_out_header {
+            len: size_of::<fuse_out_header>() as u32,
+            error: 0,
+            unique: self.unique,
+        };
+        let sender = self.sender.take().unwrap();
+        if let Err(err) = sender.send_fd(header, fd, offset, len) {
+            self.sender = Some(sender);
+            self.send(err.raw_os_error().unwrap_or(EIO), &[]);
+        }
+    }
+
+    /// Reply to a request with the given owned data. The data is handed over to the
+    /// sender instead of being gathered with the header into a list of buffers.
+    fn send_owned (&mut self, data: Vec<u8>) {
+        assert!(self.sender.is_some());
+        let header = fuse_out_header {
+            len: (size_of::<fuse_out_header>() + data.len()) as u32,
+            error: 0,
+            unique: self.unique,
+        };
+        let sender = self.sender.take().unwrap();
+        sender.send_owned(header, data);
+    }
+
     /// Reply to a request with the given type
     pub fn ok (mut self, data: &T) {
         as_bytes(data, |bytes| {
@@ -162,6 +233,14 @@ impl<T> ReplyRaw<T> {
         })
     }
 
+    /// Reply to a request with the given type, but send only the first len bytes
+    /// of it. Kernels with an older ABI version expect shorter versions of some structs.
+    pub fn ok_compat (mut self, data: &T, len: usize) {
+        as_bytes(data, |bytes| {
+            self.send(0, &[&bytes[0][..len]]);
+        })
+    }
+
     /// Reply to a request with the given error code
     pub fn error (mut self, err: c_int) {
         self.send(err, &[]);
@@ -223,6 +302,27 @@ impl ReplyData {
         self.reply.send(0, &[data]);
     }
 
+    /// Reply to a request with the given data that consists of multiple parts (e.g.
+    /// blocks of a cache). The parts are sent as is without concatenating them.
+    pub fn data_vectored (mut self, data: &[&[u8]]) {
+        self.reply.send(0, data);
+    }
+
+    /// Reply to a request with the given owned data (e.g. a `Vec<u8>` or `String`).
+    /// The data is passed on to the sender, so it isn't copied even if the sender
+    /// keeps it to send it later.
+    pub fn data_owned<T: Into<Vec<u8>>> (mut self, data: T) {
+        self.reply.send_owned(data.into());
+    }
+
+    /// Reply to a request with up to len bytes of the given file at the given offset
+    /// (less at the end of the file). In splice mode (FUSE_SPLICE_WRITE), the data is
+    /// spliced to the kernel driver without copying it to memory. If reading the file
+    /// fails, the request is replied with the error.
+    pub fn from_fd (mut self, fd: RawFd, offset: u64, len: usize) {
+        self.reply.send_fd(fd, offset, len);
+    }
+
     /// Reply to a request with the given error code
     pub fn error (self, err: c_int) {
         self.reply.error(err);
@@ -525,6 +625,63 @@ impl ReplyBmap {
     }
 }
 
+///
+/// Lseek Reply
+///
+#[derive(Debug)]
+pub struct ReplyLseek {
+    reply: ReplyRaw<fuse_lseek_out>,
+}
+
+impl Reply for ReplyLseek {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyLseek {
+        ReplyLseek { reply: Reply::new(unique, sender) }
+    }
+}
+
+impl ReplyLseek {
+    /// Reply to a request with the resulting offset
+    pub fn offset (self, offset: u64) {
+        self.reply.ok(&fuse_lseek_out {
+            offset: offset,
+        });
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+///
+/// Poll Reply
+///
+#[derive(Debug)]
+pub struct ReplyPoll {
+    reply: ReplyRaw<fuse_poll_out>,
+}
+
+impl Reply for ReplyPoll {
+    fn new<S: ReplySender> (unique: u64, sender: S) -> ReplyPoll {
+        ReplyPoll { reply: Reply::new(unique, sender) }
+    }
+}
+
+impl ReplyPoll {
+    /// Reply to a request with the events that are ready
+    pub fn poll (self, revents: u32) {
+        self.reply.ok(&fuse_poll_out {
+            revents: revents,
+            padding: 0,
+        });
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
 ///
 /// Directory reply
 ///
@@ -548,8 +705,8 @@ impl ReplyDirectory {
     /// value to request the next entries in further readdir calls
     pub fn add<T: AsRef<OsStr>> (&mut self, ino: u64, offset: u64, kind: FileType, name: T) -> bool {
         let name = name.as_ref().as_bytes();
-        let entlen = mem::size_of::<fuse_dirent>() + name.len();
-        let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1);  // 64bit align
+        let entlen = size_of::<fuse_dirent>() + name.len();
+        let entsize = (entlen + size_of::<u64>() - 1) & !(size_of::<u64>() - 1);  // 64bit align
         let padlen = entsize - entlen;
         if self.data.len() + entsize > self.data.capacity() { return true; }
         unsafe {
@@ -559,7 +716,77 @@ impl ReplyDirectory {
             (*pdirent).off = offset;
             (*pdirent).namelen = name.len() as u32;
             (*pdirent).typ = mode_from_kind_and_perm(kind, 0) >> 12;
-            let p = p.offset(mem::size_of_val(&*pdirent) as isize);
+            let p = p.offset(size_of_val(&*pdirent) as isize);
+            ptr::copy_nonoverlapping(name.as_ptr(), p, name.len());
+            let p = p.offset(name.len() as isize);
+            ptr::write_bytes(p, 0u8, padlen);
+            let newlen = self.data.len() + entsize;
+            self.data.set_len(newlen);
+        }
+        false
+    }
+
+    /// Reply to a request with the filled directory buffer
+    pub fn ok (mut self) {
+        self.reply.send(0, &[&self.data]);
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
+///
+/// Directory reply with attributes
+///
+#[derive(Debug)]
+pub struct ReplyDirectoryPlus {
+    reply: ReplyRaw<()>,
+    data: Vec<u8>,
+}
+
+impl ReplyDirectoryPlus {
+    /// Creates a new ReplyDirectoryPlus with a specified buffer size.
+    pub fn new<S: ReplySender> (unique: u64, sender: S, size: usize) -> ReplyDirectoryPlus {
+        ReplyDirectoryPlus {
+            reply: Reply::new(unique, sender),
+            data: Vec::with_capacity(size),
+        }
+    }
+
+    /// Add an entry with its attributes to the directory reply buffer. Returns true if
+    /// the buffer is full. Like with `ReplyEntry`, the kernel caches the entry and its
+    /// attributes for the given ttl and increases the entry's lookup count, so this
+    /// saves a lookup call per entry. The transparent offset value is used by the
+    /// kernel to request the next entries in further readdirplus calls.
+    pub fn add<T: AsRef<OsStr>> (&mut self, ino: u64, offset: u64, name: T, ttl: &Timespec, attr: &FileAttr, generation: u64) -> bool {
+        let name = name.as_ref().as_bytes();
+        let entlen = size_of::<fuse_direntplus>() + name.len();
+        let entsize = (entlen + size_of::<u64>() - 1) & !(size_of::<u64>() - 1);  // 64bit align
+        let padlen = entsize - entlen;
+        if self.data.len() + entsize > self.data.capacity() { return true; }
+        unsafe {
+            let p = self.data.as_mut_ptr().offset(self.data.len() as isize);
+            let pdirent: *mut fuse_direntplus = mem::transmute(p);
+            ptr::write(pdirent, fuse_direntplus {
+                entry_out: fuse_entry_out {
+                    nodeid: ino,
+                    generation: generation,
+                    entry_valid: ttl.sec,
+                    attr_valid: ttl.sec,
+                    entry_valid_nsec: ttl.nsec,
+                    attr_valid_nsec: ttl.nsec,
+                    attr: fuse_attr_from_attr(attr),
+                },
+                dirent: fuse_dirent {
+                    ino: ino,
+                    off: offset,
+                    namelen: name.len() as u32,
+                    typ: mode_from_kind_and_perm(attr.kind, 0) >> 12,
+                },
+            });
+            let p = p.offset(size_of_val(&*pdirent) as isize);
             ptr::copy_nonoverlapping(name.as_ptr(), p, name.len());
             let p = p.offset(name.len() as isize);
             ptr::write_bytes(p, 0u8, padlen);
@@ -614,20 +841,85 @@ impl ReplyXattr {
     }
 }
 
+///
+/// Ioctl Reply
+///
+#[derive(Debug)]
+pub struct ReplyIoctl {
+    reply: ReplyRaw<fuse_ioctl_out>,
+    flags: IoctlFlags,
+}
+
+impl ReplyIoctl {
+    /// Creates a new ReplyIoctl for an ioctl with the given flags
+    pub fn new<S: ReplySender> (unique: u64, sender: S, flags: IoctlFlags) -> ReplyIoctl {
+        ReplyIoctl { reply: Reply::new(unique, sender), flags: flags }
+    }
+
+    /// Reply to a request with the result of the ioctl and the output data
+    pub fn ioctl (mut self, result: i32, data: &[u8]) {
+        let out = fuse_ioctl_out { result: result, flags: 0, in_iovs: 0, out_iovs: 0 };
+        as_bytes(&out, |bytes| {
+            let mut bytes = bytes.to_vec();
+            bytes.push(data);
+            self.reply.send(0, &bytes);
+        });
+    }
+
+    /// Reply to an unrestricted ioctl by asking the kernel to retry it with the given
+    /// memory ranges of the calling process, given as pairs of address and length.
+    /// The kernel then sends the ioctl again with the concatenated data of the input
+    /// ranges and expects output data for the output ranges.
+    pub fn retry (mut self, in_iovs: &[(u64, u64)], out_iovs: &[(u64, u64)]) {
+        if !self.flags.contains(IoctlFlags::UNRESTRICTED) {
+            error!("Can't retry restricted ioctl {}", self.reply.unique);
+            return self.reply.error(EIO);
+        }
+        if in_iovs.len() + out_iovs.len() > FUSE_IOCTL_MAX_IOV as usize {
+            error!("Too many iovecs to retry ioctl {}", self.reply.unique);
+            return self.reply.error(EIO);
+        }
+        // Memory of a 32-bit process can only be addressed with 32 bits
+        let is_32bit = self.flags.contains(IoctlFlags::IS_32BIT) || self.flags.contains(IoctlFlags::COMPAT);
+        if is_32bit && in_iovs.iter().chain(out_iovs).any(|&(base, len)| base.checked_add(len).is_none_or(|end| end > 1 << 32)) {
+            error!("Invalid 32-bit iovec to retry ioctl {}", self.reply.unique);
+            return self.reply.error(EIO);
+        }
+        let out = fuse_ioctl_out { result: 0, flags: FUSE_IOCTL_RETRY, in_iovs: in_iovs.len() as u32, out_iovs: out_iovs.len() as u32 };
+        let iovs: Vec<fuse_ioctl_iovec> = in_iovs.iter().chain(out_iovs).map(|&(base, len)| fuse_ioctl_iovec { base: base, len: len }).collect();
+        let iovs = unsafe { slice::from_raw_parts(iovs.as_ptr() as *const u8, iovs.len() * size_of::<fuse_ioctl_iovec>()) };
+        as_bytes(&out, |bytes| {
+            let mut bytes = bytes.to_vec();
+            bytes.push(iovs);
+            self.reply.send(0, &bytes);
+        });
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error (self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::thread;
+    use std::{env, fs, process, thread};
+    use std::mem::size_of;
+    use std::os::unix::io::AsRawFd;
     use std::sync::mpsc::{channel, Sender};
     use time::Timespec;
+    use fuse::fuse_out_header;
     use super::as_bytes;
     use super::{Reply, ReplyRaw, ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
-    use super::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
+    use super::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyLseek, ReplyIoctl, ReplyPoll, ReplyDirectory};
+    use super::ReplyDirectoryPlus;
     use super::ReplyXattr;
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
-    use {FileType, FileAttr};
+    use {FileType, FileAttr, IoctlFlags};
 
     #[allow(dead_code)]
+    #[repr(C)]
     struct Data { a: u8, b: u8, c: u16 }
 
     #[test]
@@ -720,12 +1012,90 @@ mod test {
         reply.data(&[0xde, 0xad, 0xbe, 0xef]);
     }
 
+    #[test]
+    fn reply_data_from_fd () {
+        let path = env::temp_dir().join(format!("rust-fuse-reply-{}", process::id()));
+        fs::write(&path, [0x00, 0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let file = fs::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // Replies are short at the end of the file
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xde, 0xad, 0xbe, 0xef],
+            ]
+        };
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender);
+        reply.from_fd(file.as_raw_fd(), 1, 16);
+        // Errors reading the file are replied
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xf7, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender);
+        reply.from_fd(-1, 0, 16);
+    }
+
+    #[test]
+    fn reply_data_vectored () {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x15, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xde, 0xad],
+                vec![],
+                vec![0xbe, 0xef, 0x42],
+            ]
+        };
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender);
+        reply.data_vectored(&[&[0xde, 0xad], &[], &[0xbe, 0xef, 0x42]]);
+    }
+
+    #[test]
+    fn reply_data_owned () {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0xde, 0xad, 0xbe, 0xef],
+            ]
+        };
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender);
+        reply.data_owned(vec![0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    /// Sender that keeps owned data instead of sending it
+    struct KeepingSender(Sender<Vec<u8>>);
+
+    impl super::ReplySender for KeepingSender {
+        fn send(&self, _: &[&[u8]]) {
+            panic!("owned data sent by reference");
+        }
+
+        fn send_owned (&self, header: fuse_out_header, data: Vec<u8>) {
+            assert_eq!(header.len as usize, size_of::<fuse_out_header>() + data.len());
+            self.0.send(data).unwrap();
+        }
+    }
+
+    #[test]
+    fn reply_data_owned_kept () {
+        let (tx, rx) = channel();
+        let data = vec![0xde, 0xad, 0xbe, 0xef];
+        let ptr = data.as_ptr();
+        let reply: ReplyData = Reply::new(0xdeadbeef, KeepingSender(tx));
+        reply.data_owned(data);
+        // The sender got the very same buffer
+        let kept = rx.recv().unwrap();
+        assert_eq!(kept.as_ptr(), ptr);
+        assert_eq!(kept, [0xde, 0xad, 0xbe, 0xef]);
+    }
+
     #[test]
     fn reply_entry () {
         let sender = AssertSender {
             expected: if cfg!(target_os = "macos") {
                 vec![
-                    vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0xa0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -734,11 +1104,12 @@ mod test {
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
                          0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
-                         0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00],
+                         0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00,
+                         0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             } else {
                 vec![
-                    vec![0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -746,14 +1117,15 @@ mod test {
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
-                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
+                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                         0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             }
         };
         let reply: ReplyEntry = Reply::new(0xdeadbeef, sender);
         let time = Timespec::new(0x1234, 0x5678);
         let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
-            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99, blksize: 0xee };
         reply.entry(&time, &attr, 0xaa);
     }
 
@@ -762,31 +1134,33 @@ mod test {
         let sender = AssertSender {
             expected: if cfg!(target_os = "macos") {
                 vec![
-                    vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                         0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,  0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00],
+                         0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,  0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00,
+                         0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             } else {
                 vec![
-                    vec![0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,
-                         0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,  0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00],
+                         0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,  0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                         0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             }
         };
         let reply: ReplyAttr = Reply::new(0xdeadbeef, sender);
         let time = Timespec::new(0x1234, 0x5678);
         let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
-            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99, blksize: 0xee };
         reply.attr(&time, &attr);
     }
 
@@ -850,7 +1224,7 @@ mod test {
         let sender = AssertSender {
             expected: if cfg!(target_os = "macos") {
                 vec![
-                    vec![0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -859,12 +1233,13 @@ mod test {
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
                          0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
-                         0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00,  0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00,  0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             } else {
                 vec![
-                    vec![0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0xa0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
                     vec![0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -872,7 +1247,8 @@ mod test {
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
                          0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
-                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                          0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                 ]
             }
@@ -880,7 +1256,7 @@ mod test {
         let reply: ReplyCreate = Reply::new(0xdeadbeef, sender);
         let time = Timespec::new(0x1234, 0x5678);
         let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
-            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99 };
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99, blksize: 0xee };
         reply.created(&time, &attr, 0xaa, 0xbb, 0xcc);
     }
 
@@ -909,6 +1285,72 @@ mod test {
         reply.bmap(0x1234);
     }
 
+    #[test]
+    fn reply_lseek () {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyLseek = Reply::new(0xdeadbeef, sender);
+        reply.offset(0x1000);
+    }
+
+    #[test]
+    fn reply_poll () {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply: ReplyPoll = Reply::new(0xdeadbeef, sender);
+        reply.poll(0x05);
+    }
+
+    #[test]
+    fn reply_ioctl () {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                vec![0x66, 0x6f, 0x6f, 0x62],
+            ]
+        };
+        let reply = ReplyIoctl::new(0xdeadbeef, sender, IoctlFlags::default());
+        reply.ioctl(42, b"foob");
+    }
+
+    #[test]
+    fn reply_ioctl_retry () {
+        let sender = AssertSender {
+            expected: vec![
+                vec![0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                vec![0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+                vec![0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        let reply = ReplyIoctl::new(0xdeadbeef, sender, IoctlFlags::UNRESTRICTED);
+        reply.retry(&[(0x1000, 8)], &[(0x2000, 16)]);
+    }
+
+    #[test]
+    fn reply_ioctl_retry_invalid () {
+        let sender = || AssertSender {
+            expected: vec![
+                vec![0x10, 0x00, 0x00, 0x00, 0xfb, 0xff, 0xff, 0xff,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+            ]
+        };
+        // Retrying a restricted ioctl isn't allowed
+        let reply = ReplyIoctl::new(0xdeadbeef, sender(), IoctlFlags::default());
+        reply.retry(&[(0x1000, 8)], &[]);
+        // Memory of a 32-bit process can't exceed 4G
+        let reply = ReplyIoctl::new(0xdeadbeef, sender(), IoctlFlags::UNRESTRICTED | IoctlFlags::IS_32BIT);
+        reply.retry(&[(0xffff_fff0, 0x20)], &[]);
+    }
+
     #[test]
     fn reply_directory () {
         let sender = AssertSender {
@@ -926,6 +1368,48 @@ mod test {
         reply.ok();
     }
 
+    #[test]
+    fn reply_directory_plus () {
+        let sender = AssertSender {
+            expected: if cfg!(target_os = "macos") {
+                vec![
+                    vec![0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                         0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,  0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00,
+                         0x88, 0x00, 0x00, 0x00, 0x99, 0x00, 0x00, 0x00,  0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x05, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00,  0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x00, 0x00],
+                ]
+            } else {
+                vec![
+                    vec![0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00],
+                    vec![0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,  0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                         0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00,  0x55, 0x00, 0x00, 0x00, 0x66, 0x00, 0x00, 0x00,
+                         0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,  0xee, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                         0x05, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00,  0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x00, 0x00, 0x00],
+                ]
+            }
+        };
+        let mut reply = ReplyDirectoryPlus::new(0xdeadbeef, sender, 4096);
+        let time = Timespec::new(0x1234, 0x5678);
+        let attr = FileAttr { ino: 0x11, size: 0x22, blocks: 0x33, atime: time, mtime: time, ctime: time, crtime: time,
+            kind: FileType::RegularFile, perm: 0o644, nlink: 0x55, uid: 0x66, gid: 0x77, rdev: 0x88, flags: 0x99, blksize: 0xee };
+        reply.add(0xaabb, 1, "hello", &time, &attr, 0xaa);
+        reply.ok();
+    }
+
     impl super::ReplySender for Sender<()> {
         fn send(&self, _: &[&[u8]]) {
             Sender::send(self, ()).unwrap()
@@ -3,30 +3,39 @@
 //! kernel driver wants us to perform.
 //!
 
-use std::mem;
-use libc::{EIO, ENOSYS, EPROTO};
+use std::cmp;
+use std::mem::size_of;
+use libc::{c_int, EACCES, EAGAIN, EINVAL, EIO, ENOSYS, EPROTO};
 use time::Timespec;
 use argument::ArgumentIterator;
 use channel::ChannelSender;
-use Filesystem;
+use {FallocateFlags, Filesystem, IoctlFlags, KernelConfig, RenameFlags, TimeOrNow, WriteFlags};
+use kernel_config::SPLICE_FLAGS;
 use fuse::*;
 use fuse::consts::*;
 use fuse::fuse_opcode::*;
-use reply::{Reply, ReplyRaw, ReplyEmpty, ReplyDirectory};
-use session::{MAX_WRITE_SIZE, Session};
+use interrupt::{Interrupts, InterruptToken, InFlightSender};
+use notify::PollHandle;
+use reply::{Reply, ReplyRaw, ReplyEmpty, ReplyDirectory, ReplyDirectoryPlus, ReplyIoctl};
+use session::Session;
+use splice::WriteBuf;
 
-/// We generally support async reads, lookups of . and .. and writes larger than 4k
-#[cfg(not(target_os = "macos"))]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES;
+/// Size of a memory page, used to calculate the max number of pages per request
+const PAGE_SIZE: u32 = 4096;
 
-/// On OS X, we additionally support case insensitiveness, volume renames and xtimes
-/// TODO: we should eventually let the filesystem implementation decide which flags to set
-#[cfg(target_os = "macos")]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
+/// Unwrap an argument fetched from the data of the given request. If the request
+/// is malformed (i.e. the argument is missing), it's replied with EIO and the
+/// dispatch ends.
+macro_rules! try_fetch {
+    ($req:expr, $opcode:expr, $arg:expr) => (match $arg {
+        Some(arg) => arg,
+        None => { $req.malformed($opcode, EIO); return; },
+    })
+}
 
-/// Create a new request from the given buffer
-pub fn request<'a> (ch: ChannelSender, buffer: &'a [u8]) -> Option<Request<'a>> {
-    Request::new(ch, buffer)
+/// Create a new request from the given buffer and the payload that was left in a pipe
+pub fn request<'a> (ch: ChannelSender, buffer: &'a [u8], payload: Option<WriteBuf<'a>>, interrupts: &Interrupts) -> Option<Request<'a>> {
+    Request::new(ch, buffer, payload, interrupts)
 }
 
 /// Dispatch request to the given filesystem
@@ -34,37 +43,93 @@ pub fn dispatch<FS: Filesystem> (req: &Request, se: &mut Session<FS>) {
     req.dispatch(se);
 }
 
+/// Destroy the given filesystem if the kernel didn't send a destroy request before
+/// the session ended. The filesystem gets a request on behalf of this process.
+pub fn destroy<FS: Filesystem> (ch: ChannelSender, se: &mut Session<FS>) {
+    if !se.initialized || se.destroyed { return; }
+    let req = Request {
+        ch: ch,
+        header: fuse_in_header {
+            len: size_of::<fuse_in_header>() as u32,
+            opcode: FUSE_DESTROY as u32,
+            unique: 0,
+            nodeid: 0,
+            uid: unsafe { ::libc::getuid() },
+            gid: unsafe { ::libc::getgid() },
+            pid: unsafe { ::libc::getpid() } as u32,
+            padding: 0,
+        },
+        data: &[],
+        payload: None,
+        token: InterruptToken::new(),
+        interrupts: se.interrupts.clone(),
+    };
+    debug!("DESTROY (session ended)");
+    se.filesystem.destroy(&req);
+    se.destroyed = true;
+}
+
 /// Request data structure
 #[derive(Debug)]
 pub struct Request<'a> {
     /// Channel sender for sending the reply
     ch: ChannelSender,
     /// Header of the FUSE request
-    header: &'a fuse_in_header,
+    header: fuse_in_header,
     /// Operation-specific data payload
     data: &'a [u8],
+    /// Data of a write request that was left in a pipe (splice mode)
+    payload: Option<WriteBuf<'a>>,
+    /// Token that tells whether the kernel interrupted this request
+    token: InterruptToken,
+    /// In-flight requests of the session
+    interrupts: Interrupts,
 }
 
 impl<'a> Request<'a> {
     /// Create a new request from the given buffer
-    fn new (ch: ChannelSender, buffer: &'a [u8]) -> Option<Request<'a>> {
+    fn new (ch: ChannelSender, buffer: &'a [u8], payload: Option<WriteBuf<'a>>, interrupts: &Interrupts) -> Option<Request<'a>> {
         // Every request always begins with a fuse_in_header struct
         // followed by arbitrary data depending on which opcode it contains
-        if buffer.len() < mem::size_of::<fuse_in_header>() {
-            error!("Short read of FUSE request ({} < {})", buffer.len(), mem::size_of::<fuse_in_header>());
+        let header: fuse_in_header = match ArgumentIterator::new(buffer).fetch() {
+            Some(header) => header,
+            None => {
+                error!("Short read of FUSE request ({} < {})", buffer.len(), size_of::<fuse_in_header>());
+                return None;
+            },
+        };
+        // A payload that was left in a pipe completes the request
+        let spliced = payload.as_ref().map_or(0, |payload| payload.len());
+        if buffer.len() + spliced < header.len as usize {
+            error!("Short read of FUSE request ({} < {})", buffer.len() + spliced, header.len);
             return None;
         }
-        let mut data = ArgumentIterator::new(buffer);
-        let req = Request {
-            ch: ch,
-            header: data.fetch(),
-            data: data.fetch_data(),
-        };
-        if buffer.len() < req.header.len as usize {
-            error!("Short read of FUSE request ({} < {})", buffer.len(), req.header.len);
+        if (header.len as usize) < size_of::<fuse_in_header>() + spliced {
+            error!("Invalid length of FUSE request ({} < {})", header.len, size_of::<fuse_in_header>() + spliced);
             return None;
         }
-        Some(req)
+        Some(Request {
+            ch: ch,
+            data: &buffer[size_of::<fuse_in_header>()..header.len as usize - spliced],
+            payload: payload,
+            header: header,
+            token: InterruptToken::new(),
+            interrupts: interrupts.clone(),
+        })
+    }
+
+    /// Returns true if the sender of this request may access the filesystem. If the
+    /// session has an owner (allow_root), only the owner and root may. Like libfuse,
+    /// operations on already opened files and requests without a reply are allowed
+    /// for anyone.
+    fn permitted<FS: Filesystem> (&self, se: &Session<FS>, opcode: &fuse_opcode) -> bool {
+        match se.owner {
+            Some(owner) if self.header.uid != owner && self.header.uid != 0 => matches!(*opcode,
+                FUSE_READ | FUSE_WRITE | FUSE_FSYNC | FUSE_RELEASE | FUSE_READDIR | FUSE_READDIRPLUS |
+                FUSE_FSYNCDIR | FUSE_RELEASEDIR | FUSE_INTERRUPT | FUSE_FORGET | FUSE_BATCH_FORGET |
+                FUSE_NOTIFY_REPLY),
+            _ => true,
+        }
     }
 
     /// Dispatch request to the given filesystem.
@@ -80,14 +145,27 @@ impl<'a> Request<'a> {
             },
         };
         let mut data = ArgumentIterator::new(self.data);
+        match opcode {
+            // Operations without a reply can't be interrupted
+            FUSE_INTERRUPT | FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_NOTIFY_REPLY => (),
+            // Mark the request as interrupted if an interrupt for it arrived early
+            _ => if let Some(unique) = se.interrupts.check(self.header.unique, &self.token) {
+                // Make the kernel re-send an interrupt whose request didn't arrive
+                debug!("INTERRUPT({}) request not found, try again", unique);
+                let reply: ReplyEmpty = Reply::new(unique, self.ch);
+                reply.error(EAGAIN);
+            },
+        }
         match opcode {
             // Filesystem initialization
             FUSE_INIT => {
+                // Kernels before ABI 7.36 send a shorter init struct without flags2
+                let len = match self.data.len() < size_of::<fuse_init_in>() { true => FUSE_COMPAT_INIT_IN_SIZE, false => size_of::<fuse_init_in>() };
+                let arg: fuse_init_in = try_fetch!(self, opcode, data.fetch_compat(len));
                 let reply: ReplyRaw<fuse_init_out> = self.reply();
-                let arg: &fuse_init_in = data.fetch();
-                debug!("INIT({})   kernel: ABI {}.{}, flags {:#x}, max readahead {}", self.header.unique, arg.major, arg.minor, arg.flags, arg.max_readahead);
-                // We don't support ABI versions before 7.6
-                if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
+                debug!("INIT({})   kernel: ABI {}.{}, flags {:#x}, flags2 {:#x}, max readahead {}", self.header.unique, arg.major, arg.minor, arg.flags, arg.flags2, arg.max_readahead);
+                // We don't support ABI versions before 7.9
+                if arg.major < 7 || (arg.major == 7 && arg.minor < 9) {
                     error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
                     reply.error(EPROTO);
                     return;
@@ -96,7 +174,9 @@ impl<'a> Request<'a> {
                 se.proto_major = arg.major;
                 se.proto_minor = arg.minor;
                 // Call filesystem init method and give it a chance to return an error
-                let res = se.filesystem.init(self);
+                // or to adjust the capabilities and settings offered by the kernel
+                let mut config = KernelConfig::new(arg.major, arg.minor, arg.flags, arg.max_readahead);
+                let res = se.filesystem.init(self, &mut config);
                 if let Err(err) = res {
                     reply.error(err);
                     return;
@@ -104,17 +184,35 @@ impl<'a> Request<'a> {
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
                 // supports only lower major versions, we replied with an error above.
+                // Splicing is done by the session, so the kernel driver isn't told about it
+                let flags = config.requested_capabilities();
+                se.splice = flags & SPLICE_FLAGS;
+                se.max_write = config.max_write();
+                let flags = flags & !SPLICE_FLAGS;
                 let init = fuse_init_out {
                     major: FUSE_KERNEL_VERSION,
                     minor: FUSE_KERNEL_MINOR_VERSION,
-                    max_readahead: arg.max_readahead,       // accept any readahead size
-                    flags: arg.flags & INIT_FLAGS,          // use features given in INIT_FLAGS and reported as capable
-                    unused: 0,
-                    max_write: MAX_WRITE_SIZE as u32,       // use a max write size that fits into the session's buffer
+                    max_readahead: config.max_readahead(),
+                    flags: flags,
+                    max_background: 0,                      // use kernel default
+                    congestion_threshold: 0,                // use kernel default
+                    max_write: config.max_write(),
+                    time_gran: 1,                           // timestamps have nanosecond granularity
+                    max_pages: match flags & FUSE_MAX_PAGES {   // allow requests as large as max write
+                        0 => 0,
+                        _ => cmp::min((config.max_write() - 1) / PAGE_SIZE + 1, u16::MAX as u32) as u16,
+                    },
+                    map_alignment: 0,
+                    flags2: 0,
+                    unused: [0; 7],
                 };
-                debug!("INIT({}) response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}", self.header.unique, init.major, init.minor, init.flags, init.max_readahead, init.max_write);
+                debug!("INIT({}) response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}, max pages {}", self.header.unique, init.major, init.minor, init.flags, init.max_readahead, init.max_write, init.max_pages);
                 se.initialized = true;
-                reply.ok(&init);
+                // Kernels before ABI 7.23 expect a shorter init struct
+                match arg.minor < 23 {
+                    true => reply.ok_compat(&init, FUSE_COMPAT_22_INIT_OUT_SIZE),
+                    false => reply.ok(&init),
+                }
             },
             // Any operation is invalid before initialization
             _ if !se.initialized => {
@@ -133,38 +231,69 @@ impl<'a> Request<'a> {
                 warn!("Ignoring FUSE operation {} after destroy", self.header.opcode);
                 self.reply::<ReplyEmpty>().error(EIO);
             }
+            // Only the mounting user and root may access the filesystem (allow_root)
+            _ if !self.permitted(se, &opcode) => {
+                debug!("Denying FUSE operation {} of uid {}", self.header.opcode, self.header.uid);
+                self.reply::<ReplyEmpty>().error(EACCES);
+            }
 
             FUSE_INTERRUPT => {
-                let arg: &fuse_interrupt_in = data.fetch();
+                let arg: fuse_interrupt_in = try_fetch!(self, opcode, data.fetch());
                 debug!("INTERRUPT({}) unique {}", self.header.unique, arg.unique);
-                // TODO: handle FUSE_INTERRUPT
-                self.reply::<ReplyEmpty>().error(ENOSYS);
+                // No reply. If the request isn't in-flight, the interrupt is kept until the next request arrives
+                se.interrupts.interrupt(self.header.unique, arg.unique);
             },
 
             FUSE_LOOKUP => {
-                let name = data.fetch_str();
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("LOOKUP({}) parent {:#018x}, name {:?}", self.header.unique, self.header.nodeid, name);
                 se.filesystem.lookup(self, self.header.nodeid, &name, self.reply());
             },
             FUSE_FORGET => {
-                let arg: &fuse_forget_in = data.fetch();
+                let arg: fuse_forget_in = try_fetch!(self, opcode, data.fetch());
                 debug!("FORGET({}) ino {:#018x}, nlookup {}", self.header.unique, self.header.nodeid, arg.nlookup);
                 se.filesystem.forget(self, self.header.nodeid, arg.nlookup);    // no reply
             },
+            FUSE_BATCH_FORGET => {
+                let arg: fuse_batch_forget_in = try_fetch!(self, opcode, data.fetch());
+                debug!("BATCH_FORGET({}) count {}", self.header.unique, arg.count);
+                // Don't trust the count for allocating, the data may be shorter
+                let mut nodes = Vec::with_capacity(cmp::min(arg.count as usize, self.data.len() / size_of::<fuse_forget_one>()));
+                for _ in 0..arg.count {
+                    let node: fuse_forget_one = try_fetch!(self, opcode, data.fetch());
+                    nodes.push((node.nodeid, node.nlookup));
+                }
+                se.filesystem.batch_forget(self, &nodes);                       // no reply
+            },
             FUSE_GETATTR => {
-                debug!("GETATTR({}) ino {:#018x}", self.header.unique, self.header.nodeid);
+                let arg: fuse_getattr_in = try_fetch!(self, opcode, data.fetch());
+                debug!("GETATTR({}) ino {:#018x}, flags {:#x}, fh {}", self.header.unique, self.header.nodeid, arg.getattr_flags, arg.fh);
                 se.filesystem.getattr(self, self.header.nodeid, self.reply());
             },
             FUSE_SETATTR => {
-                let arg: &fuse_setattr_in = data.fetch();
+                let arg: fuse_setattr_in = try_fetch!(self, opcode, data.fetch());
                 debug!("SETATTR({}) ino {:#018x}, valid {:#x}", self.header.unique, self.header.nodeid, arg.valid);
                 let mode = match arg.valid & FATTR_MODE { 0 => None, _ => Some(arg.mode) };
                 let uid = match arg.valid & FATTR_UID { 0 => None, _ => Some(arg.uid) };
                 let gid = match arg.valid & FATTR_GID { 0 => None, _ => Some(arg.gid) };
                 let size = match arg.valid & FATTR_SIZE { 0 => None, _ => Some(arg.size) };
-                let atime = match arg.valid & FATTR_ATIME { 0 => None, _ => Some(Timespec::new(arg.atime, arg.atimensec)) };
-                let mtime = match arg.valid & FATTR_MTIME { 0 => None, _ => Some(Timespec::new(arg.mtime, arg.mtimensec)) };
+                let atime = match arg.valid & (FATTR_ATIME | FATTR_ATIME_NOW) {
+                    0 => None,
+                    FATTR_ATIME => Some(TimeOrNow::SpecificTime(Timespec::new(arg.atime, arg.atimensec))),
+                    _ => Some(TimeOrNow::Now),
+                };
+                let mtime = match arg.valid & (FATTR_MTIME | FATTR_MTIME_NOW) {
+                    0 => None,
+                    FATTR_MTIME => Some(TimeOrNow::SpecificTime(Timespec::new(arg.mtime, arg.mtimensec))),
+                    _ => Some(TimeOrNow::Now),
+                };
+                let ctime = match arg.valid & FATTR_CTIME { 0 => None, _ => Some(Timespec::new(arg.ctime, arg.ctimensec)) };
                 let fh = match arg.valid & FATTR_FH { 0 => None, _ => Some(arg.fh) };
+                #[cfg(not(target_os = "macos"))] #[inline]
+                fn get_kill_suidgid (arg: &fuse_setattr_in) -> bool { arg.valid & FATTR_KILL_SUIDGID != 0 }
+                #[cfg(target_os = "macos")] #[inline]
+                fn get_kill_suidgid (_arg: &fuse_setattr_in) -> bool { false }
+                let kill_suidgid = get_kill_suidgid(&arg);
                 #[cfg(target_os = "macos")] #[inline]
                 fn get_macos_setattr (arg: &fuse_setattr_in) -> (Option<Timespec>, Option<Timespec>, Option<Timespec>, Option<u32>) {
                     let crtime = match arg.valid & FATTR_CRTIME { 0 => None, _ => Some(Timespec::new(arg.crtime, arg.crtimensec)) };
@@ -177,105 +306,125 @@ impl<'a> Request<'a> {
                 fn get_macos_setattr (_arg: &fuse_setattr_in) -> (Option<Timespec>, Option<Timespec>, Option<Timespec>, Option<u32>) {
                     (None, None, None, None)
                 }
-                let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
-                se.filesystem.setattr(self, self.header.nodeid, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags, self.reply());
+                let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(&arg);
+                se.filesystem.setattr(self, self.header.nodeid, mode, uid, gid, size, atime, mtime, ctime, fh, kill_suidgid, crtime, chgtime, bkuptime, flags, self.reply());
             },
             FUSE_READLINK => {
                 debug!("READLINK({}) ino {:#018x}", self.header.unique, self.header.nodeid);
                 se.filesystem.readlink(self, self.header.nodeid, self.reply());
             },
             FUSE_MKNOD => {
-                let arg: &fuse_mknod_in = data.fetch();
-                let name = data.fetch_str();
+                // Kernels before ABI 7.12 send a shorter mknod struct without umask
+                let len = match se.proto_minor < 12 { true => FUSE_COMPAT_MKNOD_IN_SIZE, false => size_of::<fuse_mknod_in>() };
+                let arg: fuse_mknod_in = try_fetch!(self, opcode, data.fetch_compat(len));
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("MKNOD({}) parent {:#018x}, name {:?}, mode {:#05o}, rdev {}", self.header.unique, self.header.nodeid, name, arg.mode, arg.rdev);
                 se.filesystem.mknod(self, self.header.nodeid, &name, arg.mode, arg.rdev, self.reply());
             },
             FUSE_MKDIR => {
-                let arg: &fuse_mkdir_in = data.fetch();
-                let name = data.fetch_str();
+                let arg: fuse_mkdir_in = try_fetch!(self, opcode, data.fetch());
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("MKDIR({}) parent {:#018x}, name {:?}, mode {:#05o}", self.header.unique, self.header.nodeid, name, arg.mode);
                 se.filesystem.mkdir(self, self.header.nodeid, &name, arg.mode, self.reply());
             },
             FUSE_UNLINK => {
-                let name = data.fetch_str();
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("UNLINK({}) parent {:#018x}, name {:?}", self.header.unique, self.header.nodeid, name);
                 se.filesystem.unlink(self, self.header.nodeid, &name, self.reply());
             },
             FUSE_RMDIR => {
-                let name = data.fetch_str();
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("RMDIR({}) parent {:#018x}, name {:?}", self.header.unique, self.header.nodeid, name);
                 se.filesystem.rmdir(self, self.header.nodeid, &name, self.reply());
             },
             FUSE_SYMLINK => {
-                let name = data.fetch_str();
-                let link = data.fetch_path();
+                let name = try_fetch!(self, opcode, data.fetch_str());
+                let link = try_fetch!(self, opcode, data.fetch_path());
                 debug!("SYMLINK({}) parent {:#018x}, name {:?}, link {:?}", self.header.unique, self.header.nodeid, name, link);
                 se.filesystem.symlink(self, self.header.nodeid, &name, &link, self.reply());
             },
             FUSE_RENAME => {
-                let arg: &fuse_rename_in = data.fetch();
-                let name = data.fetch_str();
-                let newname = data.fetch_str();
+                let arg: fuse_rename_in = try_fetch!(self, opcode, data.fetch());
+                let name = try_fetch!(self, opcode, data.fetch_str());
+                let newname = try_fetch!(self, opcode, data.fetch_str());
                 debug!("RENAME({}) parent {:#018x}, name {:?}, newparent {:#018x}, newname {:?}", self.header.unique, self.header.nodeid, name, arg.newdir, newname);
-                se.filesystem.rename(self, self.header.nodeid, &name, arg.newdir, &newname, self.reply());
+                se.filesystem.rename(self, self.header.nodeid, &name, arg.newdir, &newname, RenameFlags::default(), self.reply());
+            },
+            FUSE_RENAME2 => {
+                let arg: fuse_rename2_in = try_fetch!(self, opcode, data.fetch());
+                let name = try_fetch!(self, opcode, data.fetch_str());
+                let newname = try_fetch!(self, opcode, data.fetch_str());
+                debug!("RENAME2({}) parent {:#018x}, name {:?}, newparent {:#018x}, newname {:?}, flags {:#x}", self.header.unique, self.header.nodeid, name, arg.newdir, newname, arg.flags);
+                se.filesystem.rename(self, self.header.nodeid, &name, arg.newdir, &newname, RenameFlags::from_bits(arg.flags), self.reply());
             },
             FUSE_LINK => {
-                let arg: &fuse_link_in = data.fetch();
-                let newname = data.fetch_str();
+                let arg: fuse_link_in = try_fetch!(self, opcode, data.fetch());
+                let newname = try_fetch!(self, opcode, data.fetch_str());
                 debug!("LINK({}) ino {:#018x}, newparent {:#018x}, newname {:?}", self.header.unique, arg.oldnodeid, self.header.nodeid, newname);
                 se.filesystem.link(self, arg.oldnodeid, self.header.nodeid, &newname, self.reply());
             },
             FUSE_OPEN => {
-                let arg: &fuse_open_in = data.fetch();
+                let arg: fuse_open_in = try_fetch!(self, opcode, data.fetch());
                 debug!("OPEN({}) ino {:#018x}, flags {:#x}", self.header.unique, self.header.nodeid, arg.flags);
                 se.filesystem.open(self, self.header.nodeid, arg.flags, self.reply());
             },
             FUSE_READ => {
-                let arg: &fuse_read_in = data.fetch();
+                let arg: fuse_read_in = try_fetch!(self, opcode, data.fetch());
                 debug!("READ({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
                 se.filesystem.read(self, self.header.nodeid, arg.fh, arg.offset, arg.size, self.reply());
             },
             FUSE_WRITE => {
-                let arg: &fuse_write_in = data.fetch();
-                let data = data.fetch_data();
-                assert!(data.len() == arg.size as usize);
+                let arg: fuse_write_in = try_fetch!(self, opcode, data.fetch());
+                let memory = WriteBuf::from_slice(data.fetch_data());
+                let data = self.payload.as_ref().unwrap_or(&memory);
+                if data.len() != arg.size as usize {
+                    self.malformed(opcode, EINVAL);
+                    return;
+                }
                 debug!("WRITE({}) ino {:#018x}, fh {}, offset {}, size {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size, arg.write_flags);
-                se.filesystem.write(self, self.header.nodeid, arg.fh, arg.offset, data, arg.write_flags, self.reply());
+                let write_flags = WriteFlags::from_bits(arg.write_flags);
+                let lock_owner = match write_flags.contains(WriteFlags::LOCKOWNER) { true => Some(arg.lock_owner), false => None };
+                se.filesystem.write_buf(self, self.header.nodeid, arg.fh, arg.offset, data, write_flags, lock_owner, self.reply());
             },
             FUSE_FLUSH => {
-                let arg: &fuse_flush_in = data.fetch();
+                let arg: fuse_flush_in = try_fetch!(self, opcode, data.fetch());
                 debug!("FLUSH({}) ino {:#018x}, fh {}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.lock_owner);
                 se.filesystem.flush(self, self.header.nodeid, arg.fh, arg.lock_owner, self.reply());
             },
             FUSE_RELEASE => {
-                let arg: &fuse_release_in = data.fetch();
+                let arg: fuse_release_in = try_fetch!(self, opcode, data.fetch());
                 let flush = match arg.release_flags & FUSE_RELEASE_FLUSH { 0 => false, _ => true };
                 debug!("RELEASE({}) ino {:#018x}, fh {}, flags {:#x}, release flags {:#x}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
                 se.filesystem.release(self, self.header.nodeid, arg.fh, arg.flags, arg.lock_owner, flush, self.reply());
             },
             FUSE_FSYNC => {
-                let arg: &fuse_fsync_in = data.fetch();
+                let arg: fuse_fsync_in = try_fetch!(self, opcode, data.fetch());
                 let datasync = match arg.fsync_flags & 1 { 0 => false, _ => true };
                 debug!("FSYNC({}) ino {:#018x}, fh {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.fsync_flags);
                 se.filesystem.fsync(self, self.header.nodeid, arg.fh, datasync, self.reply());
             },
             FUSE_OPENDIR => {
-                let arg: &fuse_open_in = data.fetch();
+                let arg: fuse_open_in = try_fetch!(self, opcode, data.fetch());
                 debug!("OPENDIR({}) ino {:#018x}, flags {:#x}", self.header.unique, self.header.nodeid, arg.flags);
                 se.filesystem.opendir(self, self.header.nodeid, arg.flags, self.reply());
             },
             FUSE_READDIR => {
-                let arg: &fuse_read_in = data.fetch();
+                let arg: fuse_read_in = try_fetch!(self, opcode, data.fetch());
                 debug!("READDIR({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
-                se.filesystem.readdir(self, self.header.nodeid, arg.fh, arg.offset, ReplyDirectory::new(self.header.unique, self.ch, arg.size as usize));
+                se.filesystem.readdir(self, self.header.nodeid, arg.fh, arg.offset, ReplyDirectory::new(self.header.unique, self.sender(), arg.size as usize));
+            },
+            FUSE_READDIRPLUS => {
+                let arg: fuse_read_in = try_fetch!(self, opcode, data.fetch());
+                debug!("READDIRPLUS({}) ino {:#018x}, fh {}, offset {}, size {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.size);
+                se.filesystem.readdirplus(self, self.header.nodeid, arg.fh, arg.offset, ReplyDirectoryPlus::new(self.header.unique, self.sender(), arg.size as usize));
             },
             FUSE_RELEASEDIR => {
-                let arg: &fuse_release_in = data.fetch();
+                let arg: fuse_release_in = try_fetch!(self, opcode, data.fetch());
                 debug!("RELEASEDIR({}) ino {:#018x}, fh {}, flags {:#x}, release flags {:#x}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.flags, arg.release_flags, arg.lock_owner);
                 se.filesystem.releasedir(self, self.header.nodeid, arg.fh, arg.flags, self.reply());
             },
             FUSE_FSYNCDIR => {
-                let arg: &fuse_fsync_in = data.fetch();
+                let arg: fuse_fsync_in = try_fetch!(self, opcode, data.fetch());
                 let datasync = match arg.fsync_flags & 1 { 0 => false, _ => true };
                 debug!("FSYNCDIR({}) ino {:#018x}, fh {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.fsync_flags);
                 se.filesystem.fsyncdir(self, self.header.nodeid, arg.fh, datasync, self.reply());
@@ -285,71 +434,125 @@ impl<'a> Request<'a> {
                 se.filesystem.statfs(self, self.header.nodeid, self.reply());
             },
             FUSE_SETXATTR => {
-                let arg: &fuse_setxattr_in = data.fetch();
-                let name = data.fetch_str();
+                let arg: fuse_setxattr_in = try_fetch!(self, opcode, data.fetch());
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 let value = data.fetch_data();
-                assert!(value.len() == arg.size as usize);
+                if value.len() != arg.size as usize {
+                    self.malformed(opcode, EINVAL);
+                    return;
+                }
                 debug!("SETXATTR({}) ino {:#018x}, name {:?}, size {}, flags {:#x}", self.header.unique, self.header.nodeid, name, arg.size, arg.flags);
                 #[cfg(target_os = "macos")] #[inline]
                 fn get_position (arg: &fuse_setxattr_in) -> u32 { arg.position }
                 #[cfg(not(target_os = "macos"))] #[inline]
                 fn get_position (_arg: &fuse_setxattr_in) -> u32 { 0 }
-                se.filesystem.setxattr(self, self.header.nodeid, name, value, arg.flags, get_position(arg), self.reply());
+                se.filesystem.setxattr(self, self.header.nodeid, name, value, arg.flags, get_position(&arg), self.reply());
             },
             FUSE_GETXATTR => {
-                let arg: &fuse_getxattr_in = data.fetch();
-                let name = data.fetch_str();
+                let arg: fuse_getxattr_in = try_fetch!(self, opcode, data.fetch());
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("GETXATTR({}) ino {:#018x}, name {:?}, size {}", self.header.unique, self.header.nodeid, name, arg.size);
                 se.filesystem.getxattr(self, self.header.nodeid, name, arg.size, self.reply());
             },
             FUSE_LISTXATTR => {
-                let arg: &fuse_getxattr_in = data.fetch();
+                let arg: fuse_getxattr_in = try_fetch!(self, opcode, data.fetch());
                 debug!("LISTXATTR({}) ino {:#018x}, size {}", self.header.unique, self.header.nodeid, arg.size);
                 se.filesystem.listxattr(self, self.header.nodeid, arg.size, self.reply());
             },
             FUSE_REMOVEXATTR => {
-                let name = data.fetch_str();
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("REMOVEXATTR({}) ino {:#018x}, name {:?}", self.header.unique, self.header.nodeid, name);
                 se.filesystem.removexattr(self, self.header.nodeid, name, self.reply());
             },
             FUSE_ACCESS => {
-                let arg: &fuse_access_in = data.fetch();
+                let arg: fuse_access_in = try_fetch!(self, opcode, data.fetch());
                 debug!("ACCESS({}) ino {:#018x}, mask {:#05o}", self.header.unique, self.header.nodeid, arg.mask);
                 se.filesystem.access(self, self.header.nodeid, arg.mask, self.reply());
             },
             FUSE_CREATE => {
-                let arg: &fuse_open_in = data.fetch();
-                let name = data.fetch_str();
+                // Kernels before ABI 7.12 send a fuse_open_in instead of a fuse_create_in
+                let len = match se.proto_minor < 12 { true => FUSE_COMPAT_CREATE_IN_SIZE, false => size_of::<fuse_create_in>() };
+                let arg: fuse_create_in = try_fetch!(self, opcode, data.fetch_compat(len));
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("CREATE({}) parent {:#018x}, name {:?}, mode {:#05o}, flags {:#x}", self.header.unique, self.header.nodeid, name, arg.mode, arg.flags);
                 se.filesystem.create(self, self.header.nodeid, &name, arg.mode, arg.flags, self.reply());
             },
             FUSE_GETLK => {
-                let arg: &fuse_lk_in = data.fetch();
+                let arg: fuse_lk_in = try_fetch!(self, opcode, data.fetch());
                 debug!("GETLK({}) ino {:#018x}, fh {}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.owner);
                 se.filesystem.getlk(self, self.header.nodeid, arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, self.reply());
             },
             FUSE_SETLK | FUSE_SETLKW => {
-                let arg: &fuse_lk_in = data.fetch();
+                let arg: fuse_lk_in = try_fetch!(self, opcode, data.fetch());
                 let sleep = match opcode { FUSE_SETLKW => true, _ => false };
                 debug!("SETLK({}) ino {:#018x}, fh {}, lock owner {}", self.header.unique, self.header.nodeid, arg.fh, arg.owner);
                 se.filesystem.setlk(self, self.header.nodeid, arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ, arg.lk.pid, sleep, self.reply());
             },
             FUSE_BMAP => {
-                let arg: &fuse_bmap_in = data.fetch();
+                let arg: fuse_bmap_in = try_fetch!(self, opcode, data.fetch());
                 debug!("BMAP({}) ino {:#018x}, blocksize {}, ids {}", self.header.unique, self.header.nodeid, arg.blocksize, arg.block);
                 se.filesystem.bmap(self, self.header.nodeid, arg.blocksize, arg.block, self.reply());
             },
+            FUSE_FALLOCATE => {
+                let arg: fuse_fallocate_in = try_fetch!(self, opcode, data.fetch());
+                debug!("FALLOCATE({}) ino {:#018x}, fh {}, offset {}, length {}, mode {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.length, arg.mode);
+                se.filesystem.fallocate(self, self.header.nodeid, arg.fh, arg.offset, arg.length, FallocateFlags::from_bits(arg.mode), self.reply());
+            },
+            FUSE_LSEEK => {
+                let arg: fuse_lseek_in = try_fetch!(self, opcode, data.fetch());
+                debug!("LSEEK({}) ino {:#018x}, fh {}, offset {}, whence {}", self.header.unique, self.header.nodeid, arg.fh, arg.offset, arg.whence);
+                se.filesystem.lseek(self, self.header.nodeid, arg.fh, arg.offset, arg.whence as c_int, self.reply());
+            },
+            FUSE_COPY_FILE_RANGE => {
+                let arg: fuse_copy_file_range_in = try_fetch!(self, opcode, data.fetch());
+                debug!("COPY_FILE_RANGE({}) ino {:#018x}, fh {}, offset {}, ino_out {:#018x}, fh_out {}, offset_out {}, len {}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh_in, arg.off_in, arg.nodeid_out, arg.fh_out, arg.off_out, arg.len, arg.flags);
+                se.filesystem.copy_file_range(self, self.header.nodeid, arg.fh_in, arg.off_in, arg.nodeid_out, arg.fh_out, arg.off_out, arg.len, arg.flags, self.reply());
+            },
+            FUSE_IOCTL => {
+                let arg: fuse_ioctl_in = try_fetch!(self, opcode, data.fetch());
+                let in_data = data.fetch_data();
+                if in_data.len() != arg.in_size as usize {
+                    self.malformed(opcode, EINVAL);
+                    return;
+                }
+                debug!("IOCTL({}) ino {:#018x}, fh {}, flags {:#x}, cmd {:#x}, arg {:#x}, in_size {}, out_size {}", self.header.unique, self.header.nodeid, arg.fh, arg.flags, arg.cmd, arg.arg, arg.in_size, arg.out_size);
+                let flags = IoctlFlags::from_bits(arg.flags);
+                se.filesystem.ioctl(self, self.header.nodeid, arg.fh, flags, arg.cmd, arg.arg, in_data, arg.out_size, ReplyIoctl::new(self.header.unique, self.sender(), flags));
+            },
+            FUSE_POLL => {
+                let arg: fuse_poll_in = try_fetch!(self, opcode, data.fetch());
+                debug!("POLL({}) ino {:#018x}, fh {}, kh {}, events {:#x}, flags {:#x}", self.header.unique, self.header.nodeid, arg.fh, arg.kh, arg.events, arg.flags);
+                let ph = PollHandle::new(arg.kh, se.notifier());
+                se.filesystem.poll(self, self.header.nodeid, arg.fh, ph, arg.events, arg.flags, self.reply());
+            },
+
+            // Reply to a retrieve notification, routed back to the notifier that sent it
+            FUSE_NOTIFY_REPLY => {
+                let arg: fuse_notify_retrieve_in = try_fetch!(self, opcode, data.fetch());
+                let data = data.fetch_data();
+                debug!("NOTIFY_REPLY({}) offset {}, size {}", self.header.unique, arg.offset, arg.size);
+                if !se.retrievals.complete(self.header.unique, &data[..cmp::min(arg.size as usize, data.len())]) {
+                    warn!("Ignoring reply to unknown retrieve notification {}", self.header.unique);
+                }
+                // no reply
+            },
+            // Not implemented yet, the kernel falls back to a default behaviour
+            FUSE_SETUPMAPPING | FUSE_REMOVEMAPPING | FUSE_SYNCFS | CUSE_INIT => {
+                debug!("{:?}({}) ino {:#018x} not implemented", opcode, self.header.unique, self.header.nodeid);
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+            },
+
             #[cfg(target_os = "macos")]
             FUSE_SETVOLNAME => {                        // OS X only
-                let name = data.fetch_str();
+                let name = try_fetch!(self, opcode, data.fetch_str());
                 debug!("SETVOLNAME({}) name {:?}", self.header.unique, name);
                 se.filesystem.setvolname(self, name, self.reply());
             },
             #[cfg(target_os = "macos")]
             FUSE_EXCHANGE => {                          // OS X only
-                let arg: &fuse_exchange_in = data.fetch();
-                let oldname = data.fetch_str();
-                let newname = data.fetch_str();
+                let arg: fuse_exchange_in = try_fetch!(self, opcode, data.fetch());
+                let oldname = try_fetch!(self, opcode, data.fetch_str());
+                let newname = try_fetch!(self, opcode, data.fetch_str());
                 debug!("EXCHANGE({}) parent {:#018x}, name {:?}, newparent {:#018x}, newname {:?}, options {:#x}", self.header.unique, arg.olddir, oldname, arg.newdir, newname, arg.options);
                 se.filesystem.exchange(self, arg.olddir, &oldname, arg.newdir, &newname, arg.options, self.reply());
             },
@@ -361,10 +564,32 @@ impl<'a> Request<'a> {
         }
     }
 
+    /// Reply to a malformed request with the given error code. Requests of operations
+    /// that don't expect a reply are ignored.
+    fn malformed (&self, opcode: fuse_opcode, err: c_int) {
+        error!("Malformed FUSE request {:?}({}), replying with error {}", opcode, self.header.unique, err);
+        match opcode {
+            FUSE_INTERRUPT | FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_NOTIFY_REPLY => (),
+            _ => self.reply::<ReplyEmpty>().error(err),
+        }
+    }
+
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply> (&self) -> T {
-        Reply::new(self.header.unique, self.ch)
+        Reply::new(self.header.unique, self.sender())
+    }
+
+    /// Create a sender for replying to this request. The request is tracked as
+    /// in-flight until the reply is sent, so that it can be interrupted.
+    fn sender (&self) -> InFlightSender<ChannelSender> {
+        InFlightSender::new(self.ch, self.header.unique, &self.token, &self.interrupts)
+    }
+
+    /// Returns the number of bytes of a write request's data that were left in a
+    /// pipe and weren't consumed by the filesystem
+    pub(crate) fn remaining_payload (&self) -> usize {
+        self.payload.as_ref().map_or(0, |payload| payload.remaining())
     }
 
     /// Returns the unique identifier of this request
@@ -390,4 +615,21 @@ impl<'a> Request<'a> {
     pub fn pid (&self) -> u32 {
         self.header.pid
     }
+
+    /// Returns true if the kernel interrupted this request, e.g. because the
+    /// waiting process received a signal. Interrupts are only noticed while the
+    /// session loop is receiving requests, i.e. if the operation is processed
+    /// in another thread.
+    #[inline] #[allow(dead_code)]
+    pub fn is_interrupted (&self) -> bool {
+        self.token.is_interrupted()
+    }
+
+    /// Returns a token for checking whether the kernel interrupted this request.
+    /// Unlike the request, the token can be sent to another thread together with
+    /// the reply. Interrupted operations should be replied with EINTR.
+    #[inline] #[allow(dead_code)]
+    pub fn interrupt_token (&self) -> InterruptToken {
+        self.token.clone()
+    }
 }
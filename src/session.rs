@@ -6,15 +6,23 @@
 //! operations under its mount point.
 //!
 
-use std::io;
-use std::ffi::OsStr;
-use std::fmt;
+use std::{cmp, io};
 use std::path::{PathBuf, Path};
-use thread_scoped::{scoped, JoinGuard};
-use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use channel::{self, Channel};
-use Filesystem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use libc::{self, EAGAIN, EINTR, ENODEV, ENOENT};
+use channel::{Channel, ChannelSender};
+use fuse::consts::{FUSE_SPLICE_MOVE, FUSE_SPLICE_READ, FUSE_SPLICE_WRITE};
+use interrupt::Interrupts;
+use mount_options::{MountOption, MountOptions};
+use notify::{Notifier, Retrievals};
+use {ConcurrentFilesystem, Filesystem};
 use request;
+use shutdown::{ExitReason, Unmounter};
+use splice::{self, Pipe, WriteBuf};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on OS X
@@ -23,7 +31,10 @@ pub const MAX_WRITE_SIZE: usize = 16*1024*1024;
 
 /// Size of the buffer for reading a request from the kernel. Since the kernel may send
 /// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+pub const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+
+/// Max time to wait for outstanding replies after the session loop ended
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// The session data structure
 #[derive(Debug)]
@@ -40,21 +51,55 @@ pub struct Session<FS: Filesystem> {
     pub initialized: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: bool,
+    /// Requests that haven't been replied yet and may be interrupted
+    pub interrupts: Interrupts,
+    /// Retrieve notifications that wait for a reply from the kernel
+    pub retrievals: Retrievals,
+    /// Splice capabilities (FUSE_SPLICE_*) that were negotiated on init
+    pub splice: u32,
+    /// Max size of write requests that was negotiated on init
+    pub max_write: u32,
+    /// Uid of the mounting user if only it and root may access the filesystem
+    pub owner: Option<u32>,
+    /// Pipe for receiving requests in splice mode (created on first use)
+    pipe: Option<Pipe>,
+    /// True if workers should use their own clone of the connection
+    clone_fd: bool,
+    /// Unmounter for ending the session loop
+    unmounter: Unmounter,
 }
 
 impl<FS: Filesystem> Session<FS> {
     /// Create a new session by mounting the given filesystem to the given mountpoint
-    pub fn new (filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<Session<FS>> {
+    pub fn new (filesystem: FS, mountpoint: &Path, options: &MountOptions) -> io::Result<Session<FS>> {
         info!("Mounting {}", mountpoint.display());
-        Channel::new(mountpoint, options).map(
-            |ch| Session {
-                filesystem: filesystem,
-                ch: ch,
-                proto_major: 0,
-                proto_minor: 0,
-                initialized: false,
-                destroyed: false,
-            })
+        let mut se = Session::with_channel(filesystem, Channel::new(mountpoint, options)?);
+        // With allow_root, the kernel allows everyone and the session denies others
+        if options.options().contains(&MountOption::AllowRoot) {
+            se.owner = Some(unsafe { libc::getuid() });
+        }
+        Ok(se)
+    }
+
+    /// Create a new session that talks to the kernel driver using the given channel
+    pub(crate) fn with_channel (filesystem: FS, ch: Channel) -> Session<FS> {
+        let unmounter = Unmounter::new(ch.mountpoint(), ch.connection());
+        Session {
+            filesystem: filesystem,
+            ch: ch,
+            proto_major: 0,
+            proto_minor: 0,
+            initialized: false,
+            destroyed: false,
+            interrupts: Interrupts::new(),
+            retrievals: Retrievals::new(),
+            splice: 0,
+            max_write: MAX_WRITE_SIZE as u32,
+            owner: None,
+            pipe: None,
+            clone_fd: false,
+            unmounter: unmounter,
+        }
     }
 
     /// Return path of the mounted filesystem
@@ -62,93 +107,437 @@ impl<FS: Filesystem> Session<FS> {
         &self.ch.mountpoint()
     }
 
+    /// Let every worker of the multi-threaded session loop use its own clone of the
+    /// connection to the kernel driver (FUSE_DEV_IOC_CLONE). The kernel then queues
+    /// requests per worker, which reduces contention on a single fd. If cloning isn't
+    /// supported, workers fall back to sharing the connection.
+    pub fn set_clone_fd (&mut self, enable: bool) {
+        self.clone_fd = enable;
+    }
+
+    /// Returns a notifier for sending notifications to the kernel. Notifiers can be
+    /// sent to other threads. After the session ended, they fail with EBADF.
+    pub fn notifier (&self) -> Notifier {
+        Notifier::new(self.ch.guarded_sender(), self.retrievals.clone())
+    }
+
+    /// Returns an unmounter for ending the session loop by unmounting the filesystem.
+    /// Unmounters can be sent to other threads or be used to unmount on signals.
+    pub fn unmounter (&self) -> Unmounter {
+        self.unmounter.clone()
+    }
+
     /// Run the session loop that receives kernel requests and dispatches them to method
     /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
     /// having multiple buffers (which take up much memory), but the filesystem methods
-    /// may run concurrent by spawning threads.
-    pub fn run (&mut self) -> io::Result<()> {
+    /// may run concurrent by spawning threads. After the filesystem was unmounted, it
+    /// waits for outstanding replies and destroys the filesystem. Returns why the
+    /// session ended.
+    pub fn run (&mut self) -> io::Result<ExitReason> {
+        let res = self.run_loop(&AtomicBool::new(false));
+        self.shutdown(res)
+    }
+
+    /// Receive and dispatch requests until the session loop should quit or the given
+    /// flag is set. The flag is only checked between requests.
+    fn run_loop (&mut self, stop: &AtomicBool) -> io::Result<ExitReason> {
         // Buffer for receiving requests from the kernel. Only one is allocated and
         // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
-        loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(&mut buffer) {
-                Ok(()) => match request::request(self.ch.sender(), &buffer) {
+        let mut buffer: Vec<u8> = Vec::with_capacity(self.buffer_size());
+        while !stop.load(Ordering::SeqCst) {
+            if let Some(reason) = self.process(&mut buffer)? {
+                return Ok(reason);
+            }
+        }
+        Ok(ExitReason::Unmounted)
+    }
+
+    /// Returns the size of buffers for receiving requests. Before the init operation,
+    /// a buffer must hold the largest possible request, afterwards the max write size
+    /// that was negotiated is known.
+    fn buffer_size (&self) -> usize {
+        if self.initialized { self.max_write as usize + 4096 } else { BUFFER_SIZE }
+    }
+
+    /// Receive the next request from the kernel and dispatch it. Returns the reason
+    /// if the session loop should quit.
+    pub(crate) fn process (&mut self, buffer: &mut Vec<u8>) -> io::Result<Option<ExitReason>> {
+        // Read the next request from the given channel to kernel driver
+        // The kernel driver makes sure that we get exactly one request per read
+        let pipe = self.take_pipe();
+        let res = match pipe {
+            Some(ref pipe) => self.ch.receive_splice(pipe, buffer),
+            None => self.ch.receive(buffer).map(|()| 0),
+        };
+        match res {
+            // Connection to the kernel driver was closed
+            Ok(_) if buffer.is_empty() => Ok(Some(ExitReason::Unmounted)),
+            Ok(spliced) => {
+                let payload = match pipe {
+                    Some(ref pipe) if spliced > 0 => Some(WriteBuf::from_pipe(pipe, spliced)),
+                    _ => None,
+                };
+                let (res, remaining) = match request::request(self.sender(), buffer, payload, &self.interrupts) {
                     // Dispatch request
-                    Some(req) => request::dispatch(&req, self),
+                    Some(req) => { request::dispatch(&req, self); (Ok(None), req.remaining_payload()) },
                     // Quit loop on illegal request
-                    None => break,
-                },
-                Err(err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => return Err(err),
+                    None => (Ok(Some(ExitReason::InvalidRequest)), spliced),
+                };
+                // Data that the filesystem didn't consume is discarded with the pipe
+                if remaining == 0 {
+                    self.pipe = pipe;
+                }
+                res
+            },
+            // Retried errors happen before anything is spliced, so the pipe is still
+            // empty and can be reused
+            Err(err) => match err.raw_os_error() {
+                // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                Some(ENOENT) => { self.pipe = pipe; Ok(None) },
+                // Interrupted system call, retry
+                Some(EINTR) => { self.pipe = pipe; Ok(None) },
+                // Explicitly try again
+                Some(EAGAIN) => { self.pipe = pipe; Ok(None) },
+                // Filesystem was unmounted, quit the loop
+                Some(ENODEV) => Ok(Some(ExitReason::Unmounted)),
+                // Unhandled error
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Returns a sender for replies, which splices data from files in splice mode
+    fn sender (&self) -> ChannelSender {
+        match self.splice & FUSE_SPLICE_WRITE {
+            0 => self.ch.sender(),
+            _ => self.ch.sender().with_splice(self.splice & FUSE_SPLICE_MOVE != 0),
+        }
+    }
+
+    /// Take the pipe for receiving requests if splice mode is enabled. The pipe is
+    /// created on first use. If that fails, requests are received without splicing.
+    fn take_pipe (&mut self) -> Option<Pipe> {
+        if self.splice & FUSE_SPLICE_READ == 0 {
+            return None;
+        }
+        if self.pipe.is_none() {
+            match Pipe::new(splice::receive_pipe_size(self.max_write)) {
+                Ok(pipe) => self.pipe = Some(pipe),
+                Err(err) => {
+                    warn!("Failed to create pipe for splicing, receiving requests without splicing: {}", err);
+                    self.splice &= !FUSE_SPLICE_READ;
                 },
             }
         }
-        Ok(())
+        self.pipe.take()
+    }
+
+    /// Finish the session after the session loop ended with the given result. Waits
+    /// for outstanding replies and destroys the filesystem if the kernel didn't.
+    fn shutdown (&mut self, res: io::Result<ExitReason>) -> io::Result<ExitReason> {
+        let stopped = self.unmounter.finish();
+        if !self.interrupts.wait_idle(SHUTDOWN_TIMEOUT) {
+            warn!("Timeout waiting for outstanding replies of {}", self.mountpoint().display());
+        }
+        request::destroy(self.ch.sender(), self);
+        match res {
+            // Tell if the filesystem was unmounted using an unmounter
+            Ok(ExitReason::Unmounted) => Ok(stopped.unwrap_or(ExitReason::Unmounted)),
+            res => res,
+        }
     }
 }
 
-impl<'a, FS: Filesystem+Send+'a> Session<FS> {
-    /// Run the session loop in a background thread
-    pub unsafe fn spawn (self) -> io::Result<BackgroundSession<'a>> {
-        BackgroundSession::new(self)
+impl<T: ConcurrentFilesystem> Session<Arc<T>> {
+    /// Run the session loop with the given number of worker threads. Every worker
+    /// receives and dispatches kernel requests concurrently using its own buffer, so
+    /// a slow operation doesn't block operations of other processes. All workers
+    /// share the concurrent filesystem. The init operation is done before starting
+    /// the workers. If a worker fails, the filesystem is unmounted to stop the other
+    /// workers and the first failure is returned.
+    pub fn run_mt (&mut self, threads: usize) -> io::Result<ExitReason> {
+        let res = self.run_mt_loop(threads);
+        self.shutdown(res)
     }
 }
 
-impl<FS: Filesystem> Drop for Session<FS> {
-    fn drop (&mut self) {
-        info!("Unmounted {}", self.mountpoint().display());
+impl<T: ConcurrentFilesystem> Session<&T> {
+    /// Run the session loop with the given number of worker threads that share the
+    /// concurrent filesystem. See `Session<Arc<T>>::run_mt`.
+    pub fn run_mt (&mut self, threads: usize) -> io::Result<ExitReason> {
+        let res = self.run_mt_loop(threads);
+        self.shutdown(res)
+    }
+}
+
+impl<FS: Filesystem+Clone+Send> Session<FS> {
+    /// Receive and dispatch requests using the given number of workers until the
+    /// session loop should quit. Only used with filesystems that are shared by
+    /// cloning, i.e. references and Arcs of concurrent filesystems.
+    fn run_mt_loop (&mut self, threads: usize) -> io::Result<ExitReason> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
+        while !self.initialized {
+            if let Some(reason) = self.process(&mut buffer)? { return Ok(reason); }
+        }
+        drop(buffer);
+        let mut workers = Vec::with_capacity(threads);
+        for _ in 0..cmp::max(threads, 1) {
+            workers.push(self.worker()?);
+        }
+        debug!("Running session loop with {} workers", workers.len());
+        // The first worker that ends stops the others. The first failure of a worker
+        // (anything but the filesystem being unmounted) is the result.
+        let stop = AtomicBool::new(false);
+        let failure = Mutex::new(None);
+        let destroyed = AtomicBool::new(false);
+        thread::scope(|s| {
+            for mut worker in workers {
+                let (stop, failure, destroyed) = (&stop, &failure, &destroyed);
+                s.spawn(move || {
+                    let res = match panic::catch_unwind(AssertUnwindSafe(|| worker.run_loop(stop))) {
+                        Ok(res) => res,
+                        Err(_) => Err(io::Error::other("session worker panicked")),
+                    };
+                    if worker.destroyed { destroyed.store(true, Ordering::SeqCst); }
+                    stop.store(true, Ordering::SeqCst);
+                    if let Ok(ExitReason::Unmounted) = res { return; }
+                    let mut failure = failure.lock().unwrap_or_else(|err| err.into_inner());
+                    if failure.is_none() {
+                        // Other workers are blocked receiving requests until unmounted
+                        if let Err(err) = worker.unmounter.unmount() {
+                            error!("Failed to unmount {} after a worker failed: {}", worker.mountpoint().display(), err);
+                        }
+                        *failure = Some(res);
+                    }
+                });
+            }
+        });
+        self.destroyed |= destroyed.into_inner();
+        failure.into_inner().unwrap_or_else(|err| err.into_inner()).unwrap_or(Ok(ExitReason::Unmounted))
+    }
+
+    /// Create a session for a worker thread that shares the connection and the
+    /// protocol state of this session
+    fn worker (&self) -> io::Result<Session<FS>> {
+        Ok(Session {
+            filesystem: self.filesystem.clone(),
+            ch: self.worker_channel()?,
+            proto_major: self.proto_major,
+            proto_minor: self.proto_minor,
+            initialized: self.initialized,
+            destroyed: self.destroyed,
+            interrupts: self.interrupts.clone(),
+            retrievals: self.retrievals.clone(),
+            splice: self.splice,
+            max_write: self.max_write,
+            owner: self.owner,
+            pipe: None,
+            clone_fd: self.clone_fd,
+            unmounter: self.unmounter.clone(),
+        })
+    }
+
+    /// Create a channel for a worker thread. If enabled, the connection is cloned,
+    /// otherwise (or if that fails) the worker shares the connection.
+    fn worker_channel (&self) -> io::Result<Channel> {
+        if self.clone_fd {
+            match self.ch.clone_connection() {
+                Ok(ch) => return Ok(ch),
+                Err(err) => warn!("Failed to clone FUSE connection, sharing it instead: {}", err),
+            }
+        }
+        self.ch.try_clone()
+    }
+}
+
+impl<FS: Filesystem+Send+'static> Session<FS> {
+    /// Run the session loop in a background thread
+    pub fn spawn (self) -> io::Result<BackgroundSession<FS>> {
+        BackgroundSession::new(self)
     }
 }
 
 /// The background session data structure
-pub struct BackgroundSession<'a> {
+#[derive(Debug)]
+pub struct BackgroundSession<FS: Filesystem+Send+'static> {
     /// Path of the mounted filesystem
     pub mountpoint: PathBuf,
-    /// Thread guard of the background session
-    pub guard: JoinGuard<'a, io::Result<()>>,
+    /// Handle of the thread that runs the session loop. It returns the result of
+    /// the session loop and the filesystem.
+    thread: Option<JoinHandle<(io::Result<ExitReason>, FS)>>,
+    /// Notifier for sending notifications to the kernel
+    notifier: Notifier,
+    /// Unmounter for ending the session loop
+    unmounter: Unmounter,
+    /// True if the filesystem was unmounted using this handle
+    unmounted: bool,
 }
 
-impl<'a> BackgroundSession<'a> {
+impl<FS: Filesystem+Send+'static> BackgroundSession<FS> {
     /// Create a new background session for the given session by running its
     /// session loop in a background thread. If the returned handle is dropped,
     /// the filesystem is unmounted and the given session ends.
-    pub unsafe fn new<FS: Filesystem+Send+'a> (se: Session<FS>) -> io::Result<BackgroundSession<'a>> {
+    pub fn new (se: Session<FS>) -> io::Result<BackgroundSession<FS>> {
         let mountpoint = se.mountpoint().to_path_buf();
-        let guard = scoped(move || {
+        let notifier = se.notifier();
+        let unmounter = se.unmounter();
+        let thread = thread::Builder::new().name("fuse-session".to_string()).spawn(move || {
             let mut se = se;
-            se.run()
-        });
-        Ok(BackgroundSession { mountpoint: mountpoint, guard: guard })
+            let res = se.run();
+            (res, se.filesystem)
+        })?;
+        Ok(BackgroundSession { mountpoint: mountpoint, thread: Some(thread), notifier: notifier, unmounter: unmounter, unmounted: false })
+    }
+
+    /// Returns a notifier for sending notifications to the kernel
+    pub fn notifier (&self) -> Notifier {
+        self.notifier.clone()
+    }
+
+    /// Returns an unmounter for ending the session loop
+    pub fn unmounter (&self) -> Unmounter {
+        self.unmounter.clone()
+    }
+
+    /// Returns true if the session loop is still running, i.e. the filesystem
+    /// wasn't unmounted yet and the session didn't fail. Doesn't block.
+    pub fn is_alive (&self) -> bool {
+        match self.thread {
+            Some(ref thread) => !thread.is_finished(),
+            None => false,
+        }
+    }
+
+    /// Unmount the filesystem, which ends the session loop with `ExitReason::Stopped`
+    pub fn unmount (&mut self) -> io::Result<()> {
+        self.unmounter.unmount()?;
+        self.unmounted = true;
+        Ok(())
+    }
+
+    /// Wait for the session loop to end and return its result together with the
+    /// filesystem. This doesn't unmount the filesystem, so unless it's unmounted
+    /// using `unmount` or by other means, it blocks forever. If the session loop
+    /// panicked, the panic is propagated.
+    pub fn join (mut self) -> (io::Result<ExitReason>, FS) {
+        let thread = self.thread.take().expect("background session already joined");
+        match thread.join() {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
     }
 }
 
-impl<'a> Drop for BackgroundSession<'a> {
+impl<FS: Filesystem+Send+'static> Drop for BackgroundSession<FS> {
     fn drop (&mut self) {
-        info!("Unmounting {}", self.mountpoint.display());
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return,
+        };
         // Unmounting the filesystem will eventually end the session loop,
         // drop the session and hence end the background thread.
-        match channel::unmount(&self.mountpoint) {
-            Ok(()) => (),
-            Err(err) => error!("Failed to unmount {}: {}", self.mountpoint.display(), err),
+        if !self.unmounted && !thread.is_finished() {
+            if let Err(err) = self.unmount() {
+                // Don't wait for a session that doesn't end
+                error!("Failed to unmount {}: {}", self.mountpoint.display(), err);
+                return;
+            }
         }
+        let _ = thread.join();
     }
 }
 
-// replace with #[derive(Debug)] if Debug ever gets implemented for
-// thread_scoped::JoinGuard
-impl<'a> fmt::Debug for BackgroundSession<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "BackgroundSession {{ mountpoint: {:?}, guard: JoinGuard<()> }}", self.mountpoint)
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::mem::size_of;
+    use std::sync::Arc;
+    use libc::{EACCES, ENOSYS};
+    use argument::ArgumentIterator;
+    use channel::Channel;
+    use fuse::{fuse_in_header, fuse_out_header, fuse_init_in, fuse_getattr_in, FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION};
+    use fuse::fuse_opcode::{self, FUSE_INIT, FUSE_GETATTR};
+    use reply::as_bytes;
+    use {ConcurrentFilesystem, ExitReason, Filesystem, ReplyAttr, Request};
+    use super::Session;
+
+    struct NullFS(u32);
+
+    impl Filesystem for NullFS {}
+
+    /// Concurrent filesystem that panics on getattr
+    struct PanicFS;
+
+    impl ConcurrentFilesystem for PanicFS {
+        fn getattr (&self, _req: &Request, _ino: u64, _reply: ReplyAttr) {
+            panic!("getattr failed");
+        }
+    }
+
+    /// Send a request of the given user with the given argument struct over the kernel
+    /// end of a channel
+    fn send_request<T> (ch: &Channel, uid: u32, unique: u64, opcode: fuse_opcode, arg: &T) {
+        let header = fuse_in_header {
+            len: (size_of::<fuse_in_header>() + size_of::<T>()) as u32,
+            opcode: opcode as u32,
+            unique: unique,
+            nodeid: 1,
+            uid: uid,
+            gid: 0,
+            pid: 0,
+            padding: 0,
+        };
+        let request = as_bytes(&header, |headerbytes| as_bytes(arg, |argbytes| [headerbytes, argbytes].concat().concat()));
+        ch.sender().send(&[&request]).unwrap();
+    }
+
+    #[test]
+    fn background_session () {
+        let (ch, session_ch) = Channel::pair().unwrap();
+        let session = Session::with_channel(NullFS(42), session_ch).spawn().unwrap();
+        // The session loop waits for requests until the kernel end is closed
+        assert!(session.is_alive());
+        drop(ch);
+        let (res, fs) = session.join();
+        assert_eq!(res.unwrap(), ExitReason::Unmounted);
+        assert_eq!(fs.0, 42);
+    }
+
+    #[test]
+    fn run_mt_worker_panic () {
+        let (ch, session_ch) = Channel::pair().unwrap();
+        let init = fuse_init_in { major: FUSE_KERNEL_VERSION, minor: FUSE_KERNEL_MINOR_VERSION, max_readahead: 0, flags: 0, flags2: 0, unused: [0; 11] };
+        send_request(&ch, 0, 1, FUSE_INIT, &init);
+        send_request(&ch, 0, 2, FUSE_GETATTR, &fuse_getattr_in { getattr_flags: 0, dummy: 0, fh: 0 });
+        // Closing the kernel end stops workers that didn't receive the getattr request
+        drop(ch);
+        let mut session = Session::with_channel(Arc::new(PanicFS), session_ch);
+        let err = session.run_mt(4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn allow_root () {
+        let (ch, session_ch) = Channel::pair().unwrap();
+        let mut session = Session::with_channel(NullFS(0), session_ch);
+        session.owner = Some(1000);
+        let session = session.spawn().unwrap();
+        let init = fuse_init_in { major: FUSE_KERNEL_VERSION, minor: FUSE_KERNEL_MINOR_VERSION, max_readahead: 0, flags: 0, flags2: 0, unused: [0; 11] };
+        send_request(&ch, 1000, 1, FUSE_INIT, &init);
+        let getattr = fuse_getattr_in { getattr_flags: 0, dummy: 0, fh: 0 };
+        send_request(&ch, 1000, 2, FUSE_GETATTR, &getattr);
+        send_request(&ch, 0, 3, FUSE_GETATTR, &getattr);
+        send_request(&ch, 1001, 4, FUSE_GETATTR, &getattr);
+        let mut buffer = Vec::with_capacity(4096);
+        ch.receive(&mut buffer).unwrap();
+        // The owner and root reach the filesystem, others are denied
+        for &(unique, error) in &[(2, ENOSYS), (3, ENOSYS), (4, EACCES)] {
+            ch.receive(&mut buffer).unwrap();
+            let header: fuse_out_header = ArgumentIterator::new(&buffer).fetch().unwrap();
+            assert_eq!((header.unique, header.error), (unique, -error));
+        }
+        drop(ch);
+        assert_eq!(session.join().0.unwrap(), ExitReason::Unmounted);
     }
 }
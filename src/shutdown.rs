@@ -0,0 +1,342 @@
+//!
+//! Shutting down sessions. An unmounter can be obtained from a session and can
+//! be used from other threads (or on signals) to unmount the filesystem, which
+//! ends the session loop. The session then waits for outstanding replies,
+//! destroys the filesystem and tells why it ended.
+//!
+
+use std::{io, mem, ptr, thread};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, Ordering};
+use libc::{self, c_int, c_void, EINTR, SIGHUP, SIGINT, SIGTERM};
+use channel;
+use mount_options::UnmountFlags;
+
+/// Reason why a session ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The filesystem was unmounted by other means (e.g. umount or fusermount -u)
+    /// or the connection to the kernel driver was closed
+    Unmounted,
+    /// The filesystem was unmounted using an unmounter
+    Stopped,
+    /// The filesystem was unmounted because the process received the given signal
+    Signal(c_int),
+    /// The kernel driver sent a request that couldn't be parsed
+    InvalidRequest,
+}
+
+/// Shared state of unmounters of a session
+#[derive(Debug, Default)]
+struct UnmountState {
+    /// Reason for unmounting if the filesystem was unmounted by an unmounter
+    reason: Option<ExitReason>,
+    /// True if the session loop ended
+    finished: bool,
+}
+
+/// Handle for unmounting the filesystem of a session. Unmounters can be cloned
+/// and sent to other threads.
+#[derive(Clone, Debug)]
+pub struct Unmounter {
+    /// Path of the mounted filesystem
+    mountpoint: PathBuf,
+    /// Id of the connection to the kernel driver (if known)
+    connection: Option<u32>,
+    /// State shared with the session
+    state: Arc<Mutex<UnmountState>>,
+}
+
+impl Unmounter {
+    /// Create a new unmounter for the filesystem mounted at the given path using
+    /// the given connection to the kernel driver
+    pub fn new (mountpoint: &Path, connection: Option<u32>) -> Unmounter {
+        Unmounter {
+            mountpoint: mountpoint.to_path_buf(),
+            connection: connection,
+            state: Arc::new(Mutex::new(UnmountState::default())),
+        }
+    }
+
+    /// Unmount the filesystem. The session loop ends with `ExitReason::Stopped`.
+    /// Does nothing if the session already ended.
+    pub fn unmount (&self) -> io::Result<()> {
+        self.unmount_with(UnmountFlags::default())
+    }
+
+    /// Unmount the filesystem using the given flags, e.g. lazily if it's busy.
+    /// The session loop ends with `ExitReason::Stopped`. Does nothing if the
+    /// session already ended.
+    pub fn unmount_with (&self, flags: UnmountFlags) -> io::Result<()> {
+        self.stop(ExitReason::Stopped, flags)
+    }
+
+    /// Abort the connection to the kernel driver. Unlike unmounting, this works
+    /// even if the filesystem hangs. Outstanding requests fail, the session loop
+    /// ends with `ExitReason::Stopped` and the session unmounts the filesystem.
+    /// Only supported on Linux.
+    pub fn abort (&self) -> io::Result<()> {
+        let connection = match self.connection {
+            Some(connection) => connection,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "unknown FUSE connection")),
+        };
+        if !self.set_reason(ExitReason::Stopped).0 {
+            return Ok(());
+        }
+        info!("Aborting connection {} of {}", connection, self.mountpoint.display());
+        channel::abort_connection(connection)
+    }
+
+    /// Unmount the filesystem if the process receives SIGINT, SIGTERM or SIGHUP.
+    /// The session loop then ends with `ExitReason::Signal`. The signal handlers
+    /// replace any previously installed handlers for these signals and unmount all
+    /// filesystems that were registered this way. The previous handlers are restored
+    /// once all of these sessions ended. If a signal is received but no filesystem
+    /// could be unmounted, the previous handlers are restored and the signal is
+    /// raised again, so e.g. SIGINT still terminates the process.
+    pub fn unmount_on_signals (&self) -> io::Result<()> {
+        let mut signals = SIGNALS.lock().unwrap();
+        if signals.is_none() {
+            *signals = Some(SignalState { unmounters: Vec::new(), previous: install_signal_handlers()? });
+        }
+        if let Some(ref mut signals) = *signals {
+            signals.unmounters.push(self.clone());
+        }
+        Ok(())
+    }
+
+    /// Unmount the filesystem for the given reason
+    fn stop (&self, reason: ExitReason, flags: UnmountFlags) -> io::Result<()> {
+        let (running, set_reason) = self.set_reason(reason);
+        if !running {
+            return Ok(());
+        }
+        info!("Unmounting {}", self.mountpoint.display());
+        let res = channel::unmount_with(&self.mountpoint, flags);
+        if res.is_err() && set_reason {
+            self.state.lock().unwrap().reason = None;
+        }
+        res
+    }
+
+    /// Remember the given reason for ending the session loop unless there already
+    /// is one. Returns whether the session loop still runs and whether the reason
+    /// was set.
+    fn set_reason (&self, reason: ExitReason) -> (bool, bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return (false, false);
+        }
+        match state.reason {
+            Some(_) => (true, false),
+            None => { state.reason = Some(reason); (true, true) },
+        }
+    }
+
+    /// Mark the session loop as ended. Returns the reason for unmounting if the
+    /// filesystem was unmounted by an unmounter. The filesystem isn't unmounted on
+    /// signals anymore.
+    pub fn finish (&self) -> Option<ExitReason> {
+        let reason = {
+            let mut state = self.state.lock().unwrap();
+            state.finished = true;
+            state.reason
+        };
+        let mut signals = SIGNALS.lock().unwrap();
+        let last = match *signals {
+            Some(ref mut signals) => {
+                signals.unmounters.retain(|unmounter| !Arc::ptr_eq(&unmounter.state, &self.state));
+                signals.unmounters.is_empty()
+            },
+            None => false,
+        };
+        if last {
+            restore_signal_handlers(&mut signals);
+        }
+        reason
+    }
+
+    /// Returns true if the session loop ended
+    fn is_finished (&self) -> bool {
+        self.state.lock().unwrap().finished
+    }
+}
+
+/// Signals that unmount filesystems
+const SIGNALS_TO_HANDLE: [c_int; 3] = [SIGINT, SIGTERM, SIGHUP];
+
+/// Installed signal handlers
+struct SignalState {
+    /// Unmounters to use if a signal is received
+    unmounters: Vec<Unmounter>,
+    /// Signal handlers that were installed before, by signal
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+/// Write end of the pipe that signal handlers use to wake up the signal thread
+/// (-1 if the signal thread isn't started yet)
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Installed signal handlers (None if the signal handlers aren't installed)
+static SIGNALS: Mutex<Option<SignalState>> = Mutex::new(None);
+
+/// Signal handler for SIGINT, SIGTERM and SIGHUP. Only async-signal-safe functions
+/// may be used here, so the signal is passed on to the signal thread.
+extern "C" fn handle_signal (signal: c_int) {
+    let byte = signal as u8;
+    unsafe { libc::write(SIGNAL_PIPE.load(Ordering::SeqCst), &byte as *const u8 as *const c_void, 1); }
+}
+
+/// Wait for signals on the read end of the signal pipe and unmount the registered
+/// filesystems
+fn signal_thread (fd: c_int) {
+    loop {
+        let mut byte = 0u8;
+        let rc = unsafe { libc::read(fd, &mut byte as *mut u8 as *mut c_void, 1) };
+        if rc < 0 && io::Error::last_os_error().raw_os_error() == Some(EINTR) { continue; }
+        if rc <= 0 { break; }
+        let signal = byte as c_int;
+        info!("Received signal {}", signal);
+        // Unmounting can block, so don't keep other threads from (un)registering
+        let unmounters = match *SIGNALS.lock().unwrap() {
+            Some(ref signals) => signals.unmounters.clone(),
+            None => Vec::new(),
+        };
+        let mut unmounted = false;
+        for unmounter in unmounters.iter().filter(|unmounter| !unmounter.is_finished()) {
+            match unmounter.stop(ExitReason::Signal(signal), UnmountFlags::default()) {
+                Ok(()) => unmounted = true,
+                Err(err) => error!("Failed to unmount {}: {}", unmounter.mountpoint.display(), err),
+            }
+        }
+        if !unmounted {
+            // Let the signal do what it did before, e.g. terminate the process
+            warn!("No filesystem unmounted on signal {}, raising it again", signal);
+            restore_signal_handlers(&mut SIGNALS.lock().unwrap());
+            unsafe { libc::raise(signal); }
+        }
+    }
+}
+
+/// Create a pipe whose ends are close-on-exec, so that they aren't inherited if
+/// other threads fork+exec
+#[cfg(target_os = "linux")]
+fn cloexec_pipe () -> io::Result<[c_int; 2]> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fds)
+}
+
+/// Create a pipe whose ends are close-on-exec. Without pipe2, the flag can only be
+/// set after creating the pipe.
+#[cfg(not(target_os = "linux"))]
+fn cloexec_pipe () -> io::Result<[c_int; 2]> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in &fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC); }
+    }
+    Ok(fds)
+}
+
+/// Start the signal thread unless it's already running
+fn start_signal_thread () -> io::Result<()> {
+    if SIGNAL_PIPE.load(Ordering::SeqCst) >= 0 {
+        return Ok(());
+    }
+    let fds = cloexec_pipe()?;
+    let fd = fds[0];
+    if let Err(err) = thread::Builder::new().name("fuse-signals".to_string()).spawn(move || signal_thread(fd)) {
+        unsafe { libc::close(fds[0]); libc::close(fds[1]); }
+        return Err(err);
+    }
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+    Ok(())
+}
+
+/// Install signal handlers for SIGINT, SIGTERM and SIGHUP and start the signal thread.
+/// Returns the previously installed handlers.
+fn install_signal_handlers () -> io::Result<Vec<(c_int, libc::sigaction)>> {
+    start_signal_thread()?;
+    let mut previous = Vec::with_capacity(SIGNALS_TO_HANDLE.len());
+    for &signal in &SIGNALS_TO_HANDLE {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = handle_signal as extern "C" fn(c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        let mut old_action: libc::sigaction = unsafe { mem::zeroed() };
+        if unsafe { libc::sigaction(signal, &action, &mut old_action) } < 0 {
+            let err = io::Error::last_os_error();
+            restore_previous_handlers(&previous);
+            return Err(err);
+        }
+        previous.push((signal, old_action));
+    }
+    Ok(previous)
+}
+
+/// Restore the given signal handlers
+fn restore_previous_handlers (previous: &[(c_int, libc::sigaction)]) {
+    for &(signal, ref action) in previous {
+        if unsafe { libc::sigaction(signal, action, ptr::null_mut()) } < 0 {
+            error!("Failed to restore handler of signal {}: {}", signal, io::Error::last_os_error());
+        }
+    }
+}
+
+/// Restore the signal handlers that were installed before ours (if ours are
+/// installed). Registered unmounters are forgotten.
+fn restore_signal_handlers (signals: &mut Option<SignalState>) {
+    if let Some(state) = signals.take() {
+        restore_previous_handlers(&state.previous);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use libc::{self, SIGHUP};
+    use mount_options::UnmountFlags;
+    use super::{ExitReason, Unmounter, SIGNALS};
+
+    /// Returns the currently installed handler of the given signal
+    fn signal_handler (signal: libc::c_int) -> libc::sighandler_t {
+        let mut action: libc::sigaction = unsafe { ::std::mem::zeroed() };
+        unsafe { libc::sigaction(signal, ::std::ptr::null(), &mut action); }
+        action.sa_sigaction
+    }
+
+    #[test]
+    fn unmount_finished () {
+        let unmounter = Unmounter::new(Path::new("/nonexistent"), None);
+        assert!(unmounter.unmount().is_err());
+        assert!(unmounter.abort().is_err());
+        assert_eq!(unmounter.finish(), None);
+        // Unmounting after the session ended does nothing
+        assert!(unmounter.unmount().is_ok());
+        assert!(unmounter.stop(ExitReason::Signal(1), UnmountFlags { lazy: true, force: false }).is_ok());
+        assert_eq!(unmounter.finish(), None);
+    }
+
+    #[test]
+    fn restore_signal_handlers () {
+        let previous = signal_handler(SIGHUP);
+        let unmounter1 = Unmounter::new(Path::new("/nonexistent"), None);
+        let unmounter2 = Unmounter::new(Path::new("/nonexistent"), None);
+        unmounter1.unmount_on_signals().unwrap();
+        unmounter2.unmount_on_signals().unwrap();
+        assert!(signal_handler(SIGHUP) != previous);
+        // Handlers stay installed until the last session ended
+        unmounter1.finish();
+        assert!(signal_handler(SIGHUP) != previous);
+        assert_eq!(SIGNALS.lock().unwrap().as_ref().map(|signals| signals.unmounters.len()), Some(1));
+        unmounter2.finish();
+        assert_eq!(signal_handler(SIGHUP), previous);
+        assert!(SIGNALS.lock().unwrap().is_none());
+    }
+}
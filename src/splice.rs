@@ -0,0 +1,371 @@
+//!
+//! Splicing data between the kernel driver, pipes and files. In splice mode, the
+//! payload of large write requests is left in a pipe instead of being copied to
+//! memory, and data replies can be spliced from a file to the kernel driver.
+//! Splicing is only supported on Linux, other systems always copy data.
+//!
+
+use std::{cmp, io, mem, ptr};
+use std::cell::{Cell, RefCell};
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+use libc::{self, c_int, c_uint, c_void, off_t, size_t, EINTR, EINVAL};
+use argument::ArgumentIterator;
+use fuse::{fuse_in_header, fuse_out_header, fuse_write_in};
+use fuse::fuse_opcode::FUSE_WRITE;
+use reply::as_bytes;
+
+/// Size of a memory page. Pipes hold data in pages, so they need some extra room
+/// for data that doesn't start at a page boundary.
+const PAGE_SIZE: usize = 4096;
+
+/// Payloads of write requests smaller than this are read to memory in splice mode,
+/// since splicing them wouldn't save anything
+const MIN_SPLICE_SIZE: usize = PAGE_SIZE;
+
+thread_local! {
+    /// Pipe for splicing replies. Replies can be sent from any thread, so every
+    /// thread uses its own pipe.
+    static REPLY_PIPE: RefCell<Option<Pipe>> = const { RefCell::new(None) };
+}
+
+/// A pipe for splicing data
+#[derive(Debug)]
+pub struct Pipe {
+    /// Read end of the pipe
+    read_fd: c_int,
+    /// Write end of the pipe
+    write_fd: c_int,
+    /// Number of bytes the pipe can hold
+    size: usize,
+}
+
+impl Pipe {
+    /// Create a pipe that can hold at least the given number of bytes. Fails if the
+    /// size exceeds the max pipe size (see /proc/sys/fs/pipe-max-size).
+    #[cfg(target_os = "linux")]
+    pub fn new (size: usize) -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut pipe = Pipe { read_fd: fds[0], write_fd: fds[1], size: 0 };
+        let rc = unsafe { libc::fcntl(pipe.write_fd, libc::F_SETPIPE_SZ, size as c_int) };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        pipe.size = rc as usize;
+        Ok(pipe)
+    }
+
+    /// Create a pipe that can hold at least the given number of bytes. Only
+    /// supported on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn new (_size: usize) -> io::Result<Pipe> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Returns the number of bytes the pipe can hold
+    pub fn size (&self) -> usize {
+        self.size
+    }
+
+    /// Move up to len bytes from the given fd (at the given offset if given) into the pipe
+    fn splice_from (&self, fd: c_int, offset: Option<u64>, len: usize, flags: c_uint) -> io::Result<usize> {
+        splice(fd, offset, self.write_fd, None, len, flags)
+    }
+
+    /// Move up to len bytes from the pipe to the given fd (at the given offset if given)
+    fn splice_to (&self, fd: c_int, offset: Option<u64>, len: usize, flags: c_uint) -> io::Result<usize> {
+        splice(self.read_fd, None, fd, offset, len, flags)
+    }
+
+    /// Read exactly len bytes from the pipe and append them to the given buffer,
+    /// which must have enough capacity
+    fn read_into (&self, buffer: &mut Vec<u8>, len: usize) -> io::Result<()> {
+        assert!(buffer.capacity() - buffer.len() >= len);
+        let end = buffer.len() + len;
+        while buffer.len() < end {
+            let rc = unsafe { libc::read(self.read_fd, buffer.as_mut_ptr().offset(buffer.len() as isize) as *mut c_void, (end - buffer.len()) as size_t) };
+            if rc < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(EINTR) { continue; }
+                return Err(err);
+            }
+            if rc == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "pipe drained"));
+            }
+            unsafe { buffer.set_len(buffer.len() + rc as usize); }
+        }
+        Ok(())
+    }
+
+    /// Write all of the given data to the pipe
+    fn write_all (&self, data: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < data.len() {
+            let rc = unsafe { libc::write(self.write_fd, data[written..].as_ptr() as *const c_void, (data.len() - written) as size_t) };
+            if rc < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(EINTR) { continue; }
+                return Err(err);
+            }
+            written += rc as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop (&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+/// Move up to len bytes between the given fds (at the given offsets if given).
+/// One of the fds must be a pipe.
+#[cfg(target_os = "linux")]
+fn splice (fd_in: c_int, off_in: Option<u64>, fd_out: c_int, off_out: Option<u64>, len: usize, flags: c_uint) -> io::Result<usize> {
+    let mut off_in = off_in.map(|off| off as libc::loff_t);
+    let mut off_out = off_out.map(|off| off as libc::loff_t);
+    loop {
+        let off_in_ptr = off_in.as_mut().map_or(ptr::null_mut(), |off| off as *mut libc::loff_t);
+        let off_out_ptr = off_out.as_mut().map_or(ptr::null_mut(), |off| off as *mut libc::loff_t);
+        let rc = unsafe { libc::splice(fd_in, off_in_ptr, fd_out, off_out_ptr, len as size_t, flags) };
+        if rc >= 0 {
+            return Ok(rc as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(EINTR) {
+            return Err(err);
+        }
+    }
+}
+
+/// Move up to len bytes between the given fds. Only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+fn splice (_fd_in: c_int, _off_in: Option<u64>, _fd_out: c_int, _off_out: Option<u64>, _len: usize, _flags: c_uint) -> io::Result<usize> {
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+/// Returns the size of a pipe for receiving requests with the given max write size
+pub fn receive_pipe_size (max_write: u32) -> usize {
+    max_write as usize + 2 * PAGE_SIZE
+}
+
+/// Receive the next request from the given channel fd using the given pipe and read
+/// it into the given buffer. The payload of a large write request is left in the
+/// pipe and its size is returned.
+pub fn receive (fd: c_int, pipe: &Pipe, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    buffer.clear();
+    let len = pipe.splice_from(fd, None, cmp::min(pipe.size(), buffer.capacity()), 0)?;
+    // Read the header and the arguments of a write request, leave its data in the pipe
+    let header_len = cmp::min(len, size_of::<fuse_in_header>() + size_of::<fuse_write_in>());
+    pipe.read_into(buffer, header_len)?;
+    let header: Option<fuse_in_header> = ArgumentIterator::new(buffer).fetch();
+    match header {
+        Some(ref header) if header.opcode == FUSE_WRITE as u32 && len - header_len >= MIN_SPLICE_SIZE => Ok(len - header_len),
+        _ => {
+            pipe.read_into(buffer, len - header_len)?;
+            Ok(0)
+        },
+    }
+}
+
+/// Send a reply consisting of the given header and up to len bytes of the given file
+/// at the given offset to the given channel fd. The file data is spliced without
+/// copying it. Returns false if the data couldn't be spliced, in which case nothing
+/// was sent and the reply should be sent by copying the data.
+pub fn send_fd (ch_fd: c_int, header: &fuse_out_header, fd: RawFd, offset: u64, len: usize, splice_move: bool) -> io::Result<bool> {
+    // Splicing less data than the header announces would corrupt the reply, so
+    // don't splice beyond the end of a regular file
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Ok(false);
+    }
+    let len = match stat.st_mode & libc::S_IFMT {
+        libc::S_IFREG => cmp::min(len as u64, (stat.st_size as u64).saturating_sub(offset)) as usize,
+        _ => len,
+    };
+    if len == 0 {
+        return Ok(false);
+    }
+    let header = fuse_out_header { len: header.len + len as u32, error: header.error, unique: header.unique };
+    let size = header.len as usize + 2 * PAGE_SIZE;
+    REPLY_PIPE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        if cell.as_ref().is_none_or(|pipe| pipe.size() < size) {
+            *cell = None;
+            match Pipe::new(size) {
+                Ok(pipe) => *cell = Some(pipe),
+                Err(_) => return Ok(false),
+            }
+        }
+        let res = match *cell {
+            Some(ref pipe) => splice_reply(pipe, ch_fd, &header, fd, offset, len, splice_move),
+            None => Ok(false),
+        };
+        // Discard data that was left in the pipe by dropping it
+        match res {
+            Ok(true) => (),
+            _ => *cell = None,
+        }
+        res
+    })
+}
+
+/// Send a reply consisting of the given header and len bytes of the given file using
+/// the given empty pipe. Returns false if nothing was sent.
+fn splice_reply (pipe: &Pipe, ch_fd: c_int, header: &fuse_out_header, fd: RawFd, offset: u64, len: usize, splice_move: bool) -> io::Result<bool> {
+    if as_bytes(header, |bytes| pipe.write_all(bytes[0])).is_err() {
+        return Ok(false);
+    }
+    let mut spliced = 0;
+    while spliced < len {
+        // Never block on a full pipe, since nobody would drain it
+        match pipe.splice_from(fd, Some(offset + spliced as u64), len - spliced, splice_flags(false, true)) {
+            Ok(0) | Err(_) => return Ok(false),
+            Ok(n) => spliced += n,
+        }
+    }
+    let total = header.len as usize;
+    let sent = pipe.splice_to(ch_fd, None, total, splice_flags(splice_move, false))?;
+    if sent < total {
+        return Err(io::Error::new(io::ErrorKind::WriteZero, "short splice of FUSE reply"));
+    }
+    Ok(true)
+}
+
+/// Returns flags for splicing
+#[cfg(target_os = "linux")]
+fn splice_flags (splice_move: bool, nonblock: bool) -> c_uint {
+    let mut flags = 0;
+    if splice_move { flags |= libc::SPLICE_F_MOVE; }
+    if nonblock { flags |= libc::SPLICE_F_NONBLOCK; }
+    flags
+}
+
+/// Returns flags for splicing. Only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+fn splice_flags (_splice_move: bool, _nonblock: bool) -> c_uint {
+    0
+}
+
+/// Data of a write request. In splice mode, the payload of a large write request is
+/// left in a pipe and can be spliced to a file without copying it to memory. Data
+/// in a pipe can only be consumed once.
+#[derive(Debug)]
+pub struct WriteBuf<'a> {
+    /// Data in memory (empty if the data is in a pipe)
+    data: &'a [u8],
+    /// Pipe that holds the data if it isn't in memory
+    pipe: Option<&'a Pipe>,
+    /// Size of the data
+    len: usize,
+    /// Number of bytes that are still in the pipe
+    remaining: Cell<usize>,
+    /// True if the data in the pipe was consumed
+    consumed: Cell<bool>,
+}
+
+impl<'a> WriteBuf<'a> {
+    /// Create a write buffer for the given data in memory
+    pub fn from_slice (data: &'a [u8]) -> WriteBuf<'a> {
+        WriteBuf { data: data, pipe: None, len: data.len(), remaining: Cell::new(0), consumed: Cell::new(false) }
+    }
+
+    /// Create a write buffer for len bytes of data in the given pipe
+    pub(crate) fn from_pipe (pipe: &'a Pipe, len: usize) -> WriteBuf<'a> {
+        WriteBuf { data: &[], pipe: Some(pipe), len: len, remaining: Cell::new(len), consumed: Cell::new(false) }
+    }
+
+    /// Returns the size of the data
+    pub fn len (&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if there's no data
+    pub fn is_empty (&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the data if it's in memory
+    pub fn as_slice (&self) -> Option<&'a [u8]> {
+        match self.pipe {
+            Some(_) => None,
+            None => Some(self.data),
+        }
+    }
+
+    /// Returns a copy of the data. Data in a pipe is read from the pipe.
+    pub fn to_vec (&self) -> io::Result<Vec<u8>> {
+        match self.pipe {
+            Some(pipe) => {
+                self.consume()?;
+                let mut data = Vec::with_capacity(self.len);
+                pipe.read_into(&mut data, self.len)?;
+                self.remaining.set(0);
+                Ok(data)
+            },
+            None => Ok(self.data.to_vec()),
+        }
+    }
+
+    /// Write the data to the given file at the given offset and return the number of
+    /// bytes written. Data in a pipe is spliced to the file without copying it.
+    pub fn write_to (&self, fd: RawFd, offset: u64) -> io::Result<usize> {
+        let pipe = match self.pipe {
+            Some(pipe) => pipe,
+            None => return pwrite_all(fd, self.data, offset),
+        };
+        self.consume()?;
+        let mut written = 0;
+        while written < self.len {
+            match pipe.splice_to(fd, Some(offset + written as u64), self.len - written, 0) {
+                Ok(0) => break,
+                Ok(n) => { written += n; self.remaining.set(self.len - written); },
+                // Files that don't support splicing (e.g. opened with O_APPEND) get a copy
+                Err(ref err) if written == 0 && err.raw_os_error() == Some(EINVAL) => {
+                    let mut data = Vec::with_capacity(self.len);
+                    pipe.read_into(&mut data, self.len)?;
+                    self.remaining.set(0);
+                    return pwrite_all(fd, &data, offset);
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Returns the number of bytes that were left in the pipe
+    pub(crate) fn remaining (&self) -> usize {
+        self.remaining.get()
+    }
+
+    /// Mark the data in the pipe as consumed. Fails if it was consumed before.
+    fn consume (&self) -> io::Result<()> {
+        if self.consumed.replace(true) {
+            return Err(io::Error::other("write data was already consumed"));
+        }
+        Ok(())
+    }
+}
+
+/// Write all of the given data to the given file at the given offset
+fn pwrite_all (fd: RawFd, data: &[u8], offset: u64) -> io::Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        let rc = unsafe { libc::pwrite(fd, data[written..].as_ptr() as *const c_void, (data.len() - written) as size_t, (offset + written as u64) as off_t) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(EINTR) { continue; }
+            return Err(err);
+        }
+        if rc == 0 { break; }
+        written += rc as usize;
+    }
+    Ok(written)
+}